@@ -0,0 +1,203 @@
+//! Calling into wasm from async code without blocking the executor.
+//!
+//! An [`AsyncRuntime`] keeps its [`Runtime`] on a thread of its own and sends calls over to it,
+//! completing a future with each call's result. Nothing about this depends on a specific
+//! executor, the futures are woken through the wakers they are polled with.
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle, Thread};
+
+use crate::error::Result;
+use crate::runtime::Runtime;
+use crate::ty::Value;
+
+// wasm3 recurses on the native stack while compiling and calling
+const NATIVE_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+type Job = Box<dyn FnOnce(&Runtime) + Send>;
+
+/// A [`Runtime`] living on a dedicated thread, with calls into it returning futures.
+///
+/// As the runtime never leaves its thread, it is set up there, including loading and linking
+/// its modules. Host functions run on that thread as well and can wait on async work with
+/// [`block_on`]. Calls are executed one after the other in the order they were made.
+///
+/// ```ignore
+/// let runtime = AsyncRuntime::new(move || {
+///     let runtime = env.create_runtime(64 * 1024)?;
+///     runtime.parse_and_load_module(bytes)?;
+///     Ok(runtime)
+/// })?;
+/// let results = runtime.call("add", &[Value::I32(1), Value::I32(2)]).await?;
+/// ```
+pub struct AsyncRuntime {
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AsyncRuntime {
+    /// Spawns the runtime's thread with a native stack of 8 MiB and sets up the runtime on it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the error `setup` fails with, waiting for it to finish.
+    pub fn new<F>(setup: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<Runtime> + Send + 'static,
+    {
+        AsyncRuntime::with_native_stack_size(NATIVE_STACK_SIZE, setup)
+    }
+
+    /// Spawns the runtime's thread with a native stack of the given size in bytes and sets up
+    /// the runtime on it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the error `setup` fails with, waiting for it to finish, and
+    /// panic if the thread can't be spawned.
+    pub fn with_native_stack_size<F>(stack_size: usize, setup: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<Runtime> + Send + 'static,
+    {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (ready, setup_result) = mpsc::sync_channel(1);
+        let thread = thread::Builder::new()
+            .name("wasm3".to_owned())
+            .stack_size(stack_size)
+            .spawn(move || {
+                let runtime = match setup() {
+                    Ok(runtime) => runtime,
+                    Err(err) => {
+                        let _ = ready.send(Err(err));
+                        return;
+                    }
+                };
+                let _ = ready.send(Ok(()));
+                for job in queue {
+                    job(&runtime);
+                }
+            })
+            .expect("failed to spawn the runtime thread");
+        match setup_result.recv() {
+            Ok(Ok(())) => Ok(AsyncRuntime {
+                jobs: Some(jobs),
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => Err(err),
+            Err(_) => panic::resume_unwind(thread.join().unwrap_err()),
+        }
+    }
+
+    /// Calls the function by the given name with dynamically typed arguments, as
+    /// [`Runtime::call`] does.
+    pub fn call(&self, name: &str, args: &[Value]) -> RuntimeFuture<Result<Vec<Value>>> {
+        let name = name.to_owned();
+        let args = args.to_vec();
+        self.run(move |runtime| runtime.call(&name, &args))
+    }
+
+    /// Runs the given closure with the runtime on its thread, for example to look up and call
+    /// statically typed [`Function`]s.
+    ///
+    /// If the closure panics, the runtime stays usable and the panic is resumed by polling the
+    /// returned future.
+    ///
+    /// [`Function`]: crate::Function
+    pub fn run<R, F>(&self, f: F) -> RuntimeFuture<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Runtime) -> R + Send + 'static,
+    {
+        let completion = Arc::new(Mutex::new(Completion {
+            result: None,
+            waker: None,
+        }));
+        let done = completion.clone();
+        let job: Job = Box::new(move |runtime| {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(runtime)));
+            let mut done = done.lock().unwrap_or_else(PoisonError::into_inner);
+            done.result = Some(result);
+            if let Some(waker) = done.waker.take() {
+                waker.wake();
+            }
+        });
+        self.jobs
+            .as_ref()
+            .and_then(|jobs| jobs.send(job).ok())
+            .expect("the runtime thread runs until the runtime is dropped");
+        RuntimeFuture(completion)
+    }
+}
+
+impl Drop for AsyncRuntime {
+    // waits for all calls that have been made to finish and drops the runtime
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Completion<R> {
+    // the panic of a job is resumed by the future instead of taking the runtime down
+    result: Option<thread::Result<R>>,
+    waker: Option<Waker>,
+}
+
+/// The future of a call into an [`AsyncRuntime`].
+///
+/// The call is made whether or not the future is polled; dropping the future only discards
+/// its result.
+pub struct RuntimeFuture<R>(Arc<Mutex<Completion<R>>>);
+
+impl<R> Future for RuntimeFuture<R> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        let mut completion = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match completion.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(payload)) => {
+                drop(completion);
+                panic::resume_unwind(payload)
+            }
+            None => {
+                completion.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Runs the given future to completion on the current thread, blocking it.
+///
+/// This lets host functions of an [`AsyncRuntime`] wait on async work. Futures that rely on an
+/// executor's reactor, like the IO types of most async runtimes, need that executor to be
+/// running on another thread.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+struct Unpark(Thread);
+
+impl std::task::Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
@@ -0,0 +1,667 @@
+//! Lightweight reading, validation and writing of the wasm binary format.
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::error::ParseError;
+
+pub(crate) const MAGIC: &[u8; 4] = b"\0asm";
+pub(crate) const VERSION: &[u8; 4] = &[1, 0, 0, 0];
+
+pub(crate) const SECTION_CUSTOM: u8 = 0;
+pub(crate) const SECTION_TYPE: u8 = 1;
+pub(crate) const SECTION_IMPORT: u8 = 2;
+pub(crate) const SECTION_FUNCTION: u8 = 3;
+pub(crate) const SECTION_TABLE: u8 = 4;
+pub(crate) const SECTION_MEMORY: u8 = 5;
+pub(crate) const SECTION_GLOBAL: u8 = 6;
+pub(crate) const SECTION_EXPORT: u8 = 7;
+pub(crate) const SECTION_START: u8 = 8;
+pub(crate) const SECTION_ELEMENT: u8 = 9;
+pub(crate) const SECTION_CODE: u8 = 10;
+pub(crate) const SECTION_DATA_COUNT: u8 = 12;
+
+pub(crate) const EXTERNAL_FUNCTION: u8 = 0;
+pub(crate) const EXTERNAL_TABLE: u8 = 1;
+pub(crate) const EXTERNAL_MEMORY: u8 = 2;
+pub(crate) const EXTERNAL_GLOBAL: u8 = 3;
+
+/// The name of the section with the given id.
+pub(crate) fn section_name(id: u8) -> &'static str {
+    match id {
+        0 => "custom",
+        1 => "type",
+        2 => "import",
+        3 => "function",
+        4 => "table",
+        5 => "memory",
+        6 => "global",
+        7 => "export",
+        8 => "start",
+        9 => "element",
+        10 => "code",
+        11 => "data",
+        12 => "data count",
+        _ => "unknown",
+    }
+}
+
+/// The position of a non-custom section in the order the spec requires sections to appear in.
+pub(crate) fn section_order(id: u8) -> Option<u8> {
+    match id {
+        1..=9 => Some(id),
+        SECTION_DATA_COUNT => Some(10),
+        SECTION_CODE => Some(11),
+        11 => Some(12),
+        _ => None,
+    }
+}
+
+/// A cursor over a wasm binary, reporting errors at absolute offsets.
+#[derive(Clone)]
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // offset of `bytes` in the whole binary
+    base: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader {
+            bytes,
+            pos: 0,
+            base: 0,
+        }
+    }
+
+    /// The absolute offset of the next byte to be read.
+    pub(crate) fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    /// The bytes read since the given absolute offset.
+    pub(crate) fn since(&self, offset: usize) -> &'a [u8] {
+        &self.bytes[offset - self.base..self.pos]
+    }
+
+    /// The bytes that have not been read yet.
+    pub(crate) fn remaining(&self) -> &'a [u8] {
+        &self.bytes[self.pos..]
+    }
+
+    pub(crate) fn error(&self, message: &'static str) -> ParseError {
+        ParseError::new(message, self.offset())
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, ParseError> {
+        let byte = *self
+            .bytes
+            .get(self.pos)
+            .ok_or_else(|| self.error("unexpected end of binary"))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    pub(crate) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(self.error("unexpected end of binary"));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Splits off a reader over the next `len` bytes.
+    pub(crate) fn sub_reader(&mut self, len: usize) -> Result<Reader<'a>, ParseError> {
+        let base = self.offset();
+        self.read_bytes(len).map(|bytes| Reader {
+            bytes,
+            pos: 0,
+            base,
+        })
+    }
+
+    fn read_leb(&mut self, bits: u32) -> Result<u64, ParseError> {
+        let start = self.offset();
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift >= bits {
+                return Err(ParseError::new("malformed LEB128 integer", start));
+            }
+            result |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, ParseError> {
+        let start = self.offset();
+        let value = self.read_leb(32)?;
+        if value > u64::from(u32::MAX) {
+            return Err(ParseError::new("malformed LEB128 integer", start));
+        }
+        Ok(value as u32)
+    }
+
+    /// Reads the element count of a vector, treating an empty reader as an empty vector.
+    pub(crate) fn read_count(&mut self) -> Result<u32, ParseError> {
+        if self.is_empty() {
+            Ok(0)
+        } else {
+            self.read_u32()
+        }
+    }
+
+    pub(crate) fn read_name(&mut self) -> Result<&'a [u8], ParseError> {
+        let len = self.read_u32()? as usize;
+        self.read_bytes(len)
+    }
+
+    /// Reads resizable limits.
+    pub(crate) fn read_limits(&mut self) -> Result<Limits, ParseError> {
+        let flags = self.read_u8()?;
+        let initial = self.read_u32()?;
+        if flags & 1 != 0 {
+            self.read_u32()?;
+        }
+        Ok(Limits {
+            initial,
+            shared: flags & 2 != 0,
+        })
+    }
+
+    pub(crate) fn read_import(&mut self) -> Result<Import, ParseError> {
+        self.read_name()?;
+        self.read_name()?;
+        let kind = self.read_u8()?;
+        let limits = match kind {
+            EXTERNAL_FUNCTION => {
+                self.read_u32()?;
+                None
+            }
+            EXTERNAL_TABLE => {
+                self.read_u8()?;
+                Some(self.read_limits()?)
+            }
+            EXTERNAL_MEMORY => Some(self.read_limits()?),
+            EXTERNAL_GLOBAL => {
+                self.read_u8()?;
+                self.read_u8()?;
+                None
+            }
+            _ => return Err(self.error("unknown import kind")),
+        };
+        Ok(Import { kind, limits })
+    }
+
+    /// Reads a single instruction of a function body or constant expression.
+    pub(crate) fn read_instruction(&mut self) -> Result<Instruction<'a>, ParseError> {
+        let start = self.pos;
+        let opcode = self.read_u8()?;
+        let mut function = None;
+        match opcode {
+            // block types are encoded as signed 33 bit integers
+            OP_BLOCK | OP_LOOP | OP_IF => {
+                self.read_leb(33)?;
+            }
+            // br, br_if, local.*, global.*, table.get, table.set
+            0x0c | 0x0d | 0x20..=0x26 => {
+                self.read_u32()?;
+            }
+            // br_table
+            0x0e => {
+                let count = self.read_u32()?;
+                for _ in 0..=count {
+                    self.read_u32()?;
+                }
+            }
+            // call, return_call, ref.func
+            0x10 | 0x12 | 0xd2 => {
+                let immediate = self.pos;
+                let index = self.read_u32()?;
+                function = Some((immediate - start..self.pos - start, index));
+            }
+            // call_indirect, return_call_indirect, loads and stores
+            0x11 | 0x13 | 0x28..=0x3e => {
+                self.read_u32()?;
+                self.read_u32()?;
+            }
+            // typed select
+            0x1c => {
+                let count = self.read_u32()?;
+                self.read_bytes(count as usize)?;
+            }
+            // memory.size, memory.grow, ref.null
+            0x3f | 0x40 | 0xd0 => {
+                self.read_u8()?;
+            }
+            0x41 => {
+                self.read_leb(32)?;
+            }
+            0x42 => {
+                self.read_leb(64)?;
+            }
+            0x43 => {
+                self.read_bytes(4)?;
+            }
+            0x44 => {
+                self.read_bytes(8)?;
+            }
+            0x00 | 0x01 | 0x05 | OP_END | 0x0f | 0x1a | 0x1b | 0x45..=0xc4 | 0xd1 => {}
+            0xfc => match self.read_u32()? {
+                // saturating truncations
+                0..=7 => {}
+                // memory.init, table.init, table.copy
+                8 | 12 | 14 => {
+                    self.read_u32()?;
+                    self.read_u32()?;
+                }
+                // memory.copy
+                10 => {
+                    self.read_u8()?;
+                    self.read_u8()?;
+                }
+                // data.drop, memory.fill, elem.drop, table.grow, table.size, table.fill
+                9 | 11 | 13 | 15..=17 => {
+                    self.read_u32()?;
+                }
+                _ => return Err(ParseError::new("unknown opcode", self.base + start)),
+            },
+            0xfd => {
+                return Err(ParseError::new(
+                    "SIMD instructions are not supported",
+                    self.base + start,
+                ))
+            }
+            0xfe => {
+                return Err(ParseError::new(
+                    "atomic instructions are not supported",
+                    self.base + start,
+                ))
+            }
+            _ => return Err(ParseError::new("unknown opcode", self.base + start)),
+        }
+        Ok(Instruction {
+            opcode,
+            bytes: &self.bytes[start..self.pos],
+            function,
+        })
+    }
+}
+
+/// An import entry.
+pub(crate) struct Import {
+    pub(crate) kind: u8,
+    /// The limits of an imported table or memory.
+    pub(crate) limits: Option<Limits>,
+}
+
+/// The limits of a table or memory.
+pub(crate) struct Limits {
+    pub(crate) initial: u32,
+    /// Whether a memory is shared between threads.
+    pub(crate) shared: bool,
+}
+
+pub(crate) const OP_BLOCK: u8 = 0x02;
+pub(crate) const OP_LOOP: u8 = 0x03;
+pub(crate) const OP_IF: u8 = 0x04;
+pub(crate) const OP_END: u8 = 0x0b;
+
+/// A single decoded instruction.
+pub(crate) struct Instruction<'a> {
+    pub(crate) opcode: u8,
+    /// The encoding of the instruction including its immediates.
+    pub(crate) bytes: &'a [u8],
+    /// A function index immediate and the range of its encoding within `bytes`.
+    pub(crate) function: Option<(Range<usize>, u32)>,
+}
+
+/// A section of a wasm binary.
+pub(crate) struct Section<'a> {
+    pub(crate) id: u8,
+    /// The offset of the section's id byte.
+    pub(crate) start: usize,
+    pub(crate) reader: Reader<'a>,
+}
+
+/// Checks the binary's header, returning a reader positioned at the first section.
+pub(crate) fn read_header(bytes: &[u8]) -> Result<Reader<'_>, ParseError> {
+    let mut reader = Reader::new(bytes);
+    if reader.read_bytes(4).ok() != Some(&MAGIC[..]) {
+        let message = if is_text_format(bytes) {
+            "the module is in the text format, convert it to the binary format first, for \
+             example with wat2wasm"
+        } else {
+            "missing wasm magic number"
+        };
+        return Err(ParseError::new(message, 0));
+    }
+    if reader.read_bytes(4).ok() != Some(&VERSION[..]) {
+        return Err(ParseError::new("unsupported wasm version", 4));
+    }
+    Ok(reader)
+}
+
+// whether the bytes look like the start of a module in the text format, which opens with
+// whitespace or comments followed by a parenthesis
+fn is_text_format(bytes: &[u8]) -> bool {
+    let start = bytes.iter().position(|byte| !byte.is_ascii_whitespace());
+    matches!(
+        start.map(|start| &bytes[start..]),
+        Some([b'(', ..]) | Some([b';', b';', ..])
+    )
+}
+
+/// Reads the next section header, returning `None` at the end of the binary.
+pub(crate) fn read_section<'a>(reader: &mut Reader<'a>) -> Result<Option<Section<'a>>, ParseError> {
+    if reader.is_empty() {
+        return Ok(None);
+    }
+    let start = reader.offset();
+    let id = reader.read_u8()?;
+    let len = reader.read_u32()? as usize;
+    let section = reader
+        .sub_reader(len)
+        .map_err(|_| ParseError::new("section extends past the end of the binary", start))?;
+    Ok(Some(Section {
+        id,
+        start,
+        reader: section,
+    }))
+}
+
+pub(crate) fn write_u32(out: &mut Vec<u8>, value: u32) {
+    let mut value = value;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub(crate) fn write_i64(out: &mut Vec<u8>, value: i64) {
+    let mut value = value;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub(crate) fn write_name(out: &mut Vec<u8>, name: &[u8]) {
+    write_u32(out, name.len() as u32);
+    out.extend_from_slice(name);
+}
+
+pub(crate) fn write_section(out: &mut Vec<u8>, id: u8, content: &[u8]) {
+    out.push(id);
+    write_name(out, content);
+}
+
+/// Validates the overall structure of a wasm binary, so that malformed modules can be rejected
+/// with a precise location before handing them to wasm3.
+///
+/// This checks the header, the section headers and their order, the import section and the
+/// framing of the function bodies, leaving the validation of the contents to wasm3.
+pub(crate) fn validate(bytes: &[u8]) -> Result<(), ParseError> {
+    let mut reader = read_header(bytes)?;
+    let mut last_order = 0;
+    let mut imported_functions = 0;
+    let mut declared_functions = None;
+    while let Some(mut section) = read_section(&mut reader)? {
+        let id = section.id;
+        if id != SECTION_CUSTOM {
+            let order = section_order(id)
+                .ok_or_else(|| ParseError::new("unknown section id", section.start))?;
+            if order <= last_order {
+                return Err(ParseError::new(
+                    "section is out of order or duplicated",
+                    section.start,
+                ));
+            }
+            last_order = order;
+        }
+        let result = match id {
+            SECTION_CUSTOM => section.reader.read_name().map(drop),
+            SECTION_IMPORT => validate_imports(&mut section.reader).map(|functions| {
+                imported_functions = functions;
+            }),
+            SECTION_FUNCTION => section.reader.read_u32().map(|count| {
+                declared_functions = Some(count);
+            }),
+            SECTION_CODE => validate_code(
+                &mut section.reader,
+                imported_functions,
+                declared_functions.unwrap_or(0),
+            ),
+            _ => Ok(()),
+        };
+        result.map_err(|err| err.in_section(id))?;
+    }
+    Ok(())
+}
+
+/// The largest initial sizes of the tables and memories a module declares or imports.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct InitialSizes {
+    pub(crate) memory_pages: u32,
+    pub(crate) table_elements: u32,
+}
+
+/// Reads the initial sizes of a module's tables and memories.
+///
+/// The binary has to have passed [`validate`].
+pub(crate) fn initial_sizes(bytes: &[u8]) -> Result<InitialSizes, ParseError> {
+    let mut sizes = InitialSizes::default();
+    let mut reader = read_header(bytes)?;
+    while let Some(mut section) = read_section(&mut reader)? {
+        let reader = &mut section.reader;
+        match section.id {
+            SECTION_IMPORT => {
+                for _ in 0..reader.read_u32()? {
+                    let import = reader.read_import()?;
+                    let initial = import.limits.map_or(0, |limits| limits.initial);
+                    match import.kind {
+                        EXTERNAL_TABLE => sizes.table_elements = sizes.table_elements.max(initial),
+                        EXTERNAL_MEMORY => sizes.memory_pages = sizes.memory_pages.max(initial),
+                        _ => (),
+                    }
+                }
+            }
+            SECTION_TABLE => {
+                for _ in 0..reader.read_u32()? {
+                    reader.read_u8()?;
+                    sizes.table_elements = sizes.table_elements.max(reader.read_limits()?.initial);
+                }
+            }
+            SECTION_MEMORY => {
+                for _ in 0..reader.read_u32()? {
+                    sizes.memory_pages = sizes.memory_pages.max(reader.read_limits()?.initial);
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(sizes)
+}
+
+fn validate_imports(reader: &mut Reader<'_>) -> Result<u32, ParseError> {
+    let count = reader.read_u32()?;
+    let mut functions = 0;
+    for _ in 0..count {
+        if reader.read_import()?.kind == EXTERNAL_FUNCTION {
+            functions += 1;
+        }
+    }
+    if !reader.is_empty() {
+        return Err(reader.error("unexpected data after the last import"));
+    }
+    Ok(functions)
+}
+
+fn validate_code(
+    reader: &mut Reader<'_>,
+    imported_functions: u32,
+    declared_functions: u32,
+) -> Result<(), ParseError> {
+    let count_offset = reader.offset();
+    let count = reader.read_u32()?;
+    if count != declared_functions {
+        return Err(ParseError::new(
+            "function and code section have inconsistent lengths",
+            count_offset,
+        ));
+    }
+    for index in 0..count {
+        let function = imported_functions + index;
+        let start = reader.offset();
+        let len = reader.read_u32().map_err(|err| err.in_function(function))?;
+        reader.sub_reader(len as usize).map_err(|_| {
+            ParseError::new("function body extends past the end of the section", start)
+                .in_function(function)
+        })?;
+    }
+    if !reader.is_empty() {
+        return Err(reader.error("unexpected data after the last function body"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (module (func (export "f") (result i32) i32.const 1))
+    const MODULE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f,
+        0x03, 0x02, 0x01, 0x00, 0x07, 0x05, 0x01, 0x01, 0x66, 0x00, 0x00, 0x0a, 0x06, 0x01, 0x04,
+        0x00, 0x41, 0x01, 0x0b,
+    ];
+
+    #[test]
+    fn test_validate_ok() {
+        assert_eq!(validate(MODULE), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_bad_magic() {
+        let err = validate(b"(module)").unwrap_err();
+        assert_eq!((err.offset(), err.section()), (0, None));
+    }
+
+    #[test]
+    fn test_validate_text_format() {
+        let err = validate(b"\n  (module)").unwrap_err();
+        assert!(err.message().contains("text format"));
+        let err = validate(b";; comment\n(module)").unwrap_err();
+        assert!(err.message().contains("text format"));
+        let err = validate(b"\x7fELF").unwrap_err();
+        assert_eq!(err.message(), "missing wasm magic number");
+    }
+
+    #[test]
+    fn test_validate_bad_version() {
+        let err = validate(b"\0asm\x02\0\0\0").unwrap_err();
+        assert_eq!(err.offset(), 4);
+    }
+
+    #[test]
+    fn test_validate_truncated_section() {
+        let err = validate(&MODULE[..MODULE.len() - 1]).unwrap_err();
+        assert_eq!((err.offset(), err.section()), (26, None));
+    }
+
+    #[test]
+    fn test_validate_misordered_section() {
+        let mut module = MODULE[..8].to_vec();
+        // export section followed by an empty type section
+        module.extend_from_slice(&[0x07, 0x01, 0x00, 0x01, 0x01, 0x00]);
+        let err = validate(&module).unwrap_err();
+        assert_eq!(err.offset(), 11);
+    }
+
+    #[test]
+    fn test_validate_function_body_overrun() {
+        let mut module = MODULE.to_vec();
+        // claim the function body is a byte longer than it is
+        module[29] = 0x05;
+        let err = validate(&module).unwrap_err();
+        assert_eq!(
+            (err.offset(), err.section(), err.function()),
+            (29, Some(SECTION_CODE), Some(0))
+        );
+    }
+
+    #[test]
+    fn test_initial_sizes() {
+        assert_eq!(initial_sizes(MODULE), Ok(InitialSizes::default()));
+        let mut module = MODULE[..8].to_vec();
+        // (import "env" "t" (table 3 funcref)) (table 2 funcref) (memory 4 5)
+        module.extend_from_slice(&[
+            0x02, 0x0b, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x01, 0x74, 0x01, 0x70, 0x00, 0x03, 0x04,
+            0x04, 0x01, 0x70, 0x00, 0x02, 0x05, 0x04, 0x01, 0x01, 0x04, 0x05,
+        ]);
+        assert_eq!(validate(&module), Ok(()));
+        assert_eq!(
+            initial_sizes(&module),
+            Ok(InitialSizes {
+                memory_pages: 4,
+                table_elements: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_read_leb() {
+        let mut reader = Reader::new(&[0xe5, 0x8e, 0x26, 0x80, 0x00]);
+        assert_eq!(reader.read_u32(), Ok(624_485));
+        assert_eq!(reader.read_u32(), Ok(0));
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_write_leb() {
+        let mut out = Vec::new();
+        write_u32(&mut out, 624_485);
+        write_i64(&mut out, -128);
+        write_i64(&mut out, 64);
+        assert_eq!(out, [0xe5, 0x8e, 0x26, 0x80, 0x7f, 0xc0, 0x00]);
+    }
+
+    #[test]
+    fn test_read_instruction() {
+        // call 300, i64.const -1, i32.load offset=4, end
+        let mut reader = Reader::new(&[0x10, 0xac, 0x02, 0x42, 0x7f, 0x28, 0x02, 0x04, 0x0b]);
+        let call = reader.read_instruction().unwrap();
+        assert_eq!((call.opcode, call.function), (0x10, Some((1..3, 300))));
+        assert_eq!(reader.read_instruction().unwrap().bytes, [0x42, 0x7f]);
+        assert_eq!(reader.read_instruction().unwrap().bytes, [0x28, 0x02, 0x04]);
+        assert_eq!(reader.read_instruction().unwrap().opcode, OP_END);
+        assert!(reader.is_empty());
+        let err = Reader::new(&[0xfd, 0x00]).read_instruction().err().unwrap();
+        assert_eq!(err.offset(), 0);
+    }
+
+    #[test]
+    fn test_read_leb_overflow() {
+        let mut reader = Reader::new(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(reader.read_u32().unwrap_err().offset(), 0);
+    }
+}
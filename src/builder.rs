@@ -0,0 +1,187 @@
+use alloc::boxed::Box;
+use core::any::Any;
+
+use crate::environment::Environment;
+use crate::error::{Error, Result};
+use crate::runtime::{AnyData, Runtime};
+use crate::utils::MaybeSend;
+
+// the size of a value slot on wasm3's stack
+pub(crate) const SLOT_SIZE: u32 = if cfg!(feature = "use-32bit-slots") {
+    4
+} else {
+    8
+};
+
+pub(crate) const PAGE_SIZE: u32 = 64 * 1024;
+const MAX_PAGES: u32 = 65536;
+
+#[derive(Debug, Clone, Copy)]
+enum StackSize {
+    Bytes(u32),
+    Slots(u32),
+}
+
+/// Limits a [`Runtime`] enforces on the modules loaded into it.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct RuntimeConfig {
+    pub(crate) memory_pages: Option<u32>,
+    pub(crate) table_elements: Option<u32>,
+    pub(crate) compile_eagerly: bool,
+    pub(crate) record_call_stats: bool,
+    pub(crate) deterministic: bool,
+}
+
+/// A builder for a [`Runtime`] with explicit resource configuration.
+///
+/// ```ignore
+/// let runtime = RuntimeBuilder::new(&env)
+///     .stack_size_bytes(256 * 1024)
+///     .memory_limit_pages(16)
+///     .compile_eagerly(true)
+///     .build()?;
+/// ```
+pub struct RuntimeBuilder {
+    environment: Environment,
+    stack_size: StackSize,
+    config: RuntimeConfig,
+    user_data: Option<Box<AnyData>>,
+}
+
+impl RuntimeBuilder {
+    /// Creates a builder for a runtime in the given environment, with a stack of 64 KiB, no
+    /// limits and lazy compilation.
+    pub fn new(environment: &Environment) -> Self {
+        RuntimeBuilder {
+            environment: environment.clone(),
+            stack_size: StackSize::Bytes(64 * 1024),
+            config: RuntimeConfig::default(),
+            user_data: None,
+        }
+    }
+
+    /// Sets the size of the wasm stack in bytes, which has to be a multiple of the slot size of
+    /// 4 bytes with the `use-32bit-slots` feature and 8 bytes without it.
+    pub fn stack_size_bytes(mut self, bytes: u32) -> Self {
+        self.stack_size = StackSize::Bytes(bytes);
+        self
+    }
+
+    /// Sets the size of the wasm stack in value slots.
+    pub fn stack_size_slots(mut self, slots: u32) -> Self {
+        self.stack_size = StackSize::Slots(slots);
+        self
+    }
+
+    /// Limits the linear memory to the given number of 64 KiB pages.
+    ///
+    /// Loading a module whose memory starts out larger fails, and the memory of loaded modules
+    /// is never grown past the limit.
+    pub fn memory_limit_pages(mut self, pages: u32) -> Self {
+        self.config.memory_pages = Some(pages);
+        self
+    }
+
+    /// Limits the number of elements of tables, failing to load modules with larger tables.
+    pub fn table_limit(mut self, elements: u32) -> Self {
+        self.config.table_elements = Some(elements);
+        self
+    }
+
+    /// Attaches data to the runtime, which is accessible through [`Runtime::user_data`] and
+    /// [`CallContext::user_data`] in host functions.
+    ///
+    /// [`CallContext::user_data`]: crate::CallContext::user_data
+    pub fn user_data<T: Any + MaybeSend>(mut self, data: T) -> Self {
+        self.user_data = Some(Box::new(data));
+        self
+    }
+
+    /// Compiles all functions of a module when the first of them is looked up instead of on
+    /// their first call, which surfaces compilation errors early and keeps calls free of
+    /// compilation pauses.
+    ///
+    /// Calls to imports can only be compiled once the imports are linked, so all imports a
+    /// module calls have to be linked before any of its functions is looked up.
+    ///
+    /// With the `sync` feature enabled, modules are always compiled this way, so that calls
+    /// don't have to lock the environment shared with other runtimes.
+    pub fn compile_eagerly(mut self, eager: bool) -> Self {
+        self.config.compile_eagerly = eager;
+        self
+    }
+
+    /// Records the resources used by each call into the runtime, to be retrieved with
+    /// [`Runtime::last_call_stats`].
+    ///
+    /// This makes calls more expensive, as the whole wasm stack is scanned around each call to
+    /// determine its peak usage. Calls made back into the runtime by host functions are
+    /// accounted to the call they are made from.
+    pub fn record_call_stats(mut self, record: bool) -> Self {
+        self.config.record_call_stats = record;
+        self
+    }
+
+    /// Makes the execution of loaded modules deterministic, so that calls with the same inputs
+    /// produce the same results on every host.
+    ///
+    /// NaNs produced by float arithmetic are canonicalized, which slows down float heavy code,
+    /// modules using shared memories fail to load, and wasi linked with [`Module::link_wasi`]
+    /// reports a constant time and random bytes that are all zero.
+    ///
+    /// Modules are instrumented for this when they are loaded, which copies their bytes, even
+    /// those of modules parsed without copying them and of instances of shared modules. Use
+    /// [`SharedModule::into_deterministic`] to instrument a shared module only once.
+    ///
+    /// [`Module::link_wasi`]: crate::Module::link_wasi
+    /// [`SharedModule::into_deterministic`]: crate::SharedModule::into_deterministic
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.config.deterministic = deterministic;
+        self
+    }
+
+    /// Builds the runtime.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`Error::InvalidRuntimeConfig`] if the configuration is
+    /// invalid, and error on memory allocation failure.
+    pub fn build(self) -> Result<Runtime> {
+        let stack_bytes = match self.stack_size {
+            StackSize::Bytes(bytes) if bytes % SLOT_SIZE != 0 => {
+                return Err(Error::InvalidRuntimeConfig(
+                    "the stack size is not a multiple of the slot size",
+                ))
+            }
+            StackSize::Bytes(bytes) => bytes,
+            StackSize::Slots(slots) => slots
+                .checked_mul(SLOT_SIZE)
+                .ok_or(Error::InvalidRuntimeConfig("the stack size exceeds 4 GiB"))?,
+        };
+        if stack_bytes == 0 {
+            return Err(Error::InvalidRuntimeConfig("the stack size is zero"));
+        }
+        match self.config.memory_pages {
+            Some(0) => {
+                return Err(Error::InvalidRuntimeConfig(
+                    "the memory limit is less than a page",
+                ))
+            }
+            Some(pages) if pages > MAX_PAGES => {
+                return Err(Error::InvalidRuntimeConfig(
+                    "the memory limit exceeds the 4 GiB addressable by wasm",
+                ))
+            }
+            _ => (),
+        }
+        Runtime::with_config(&self.environment, stack_bytes, self.config, self.user_data)
+    }
+}
+
+/// The memory limit in bytes as wasm3 expects it, where 0 means no limit.
+pub(crate) fn memory_limit_bytes(pages: Option<u32>) -> u32 {
+    match pages {
+        Some(pages) if pages < MAX_PAGES => pages * PAGE_SIZE,
+        _ => 0,
+    }
+}
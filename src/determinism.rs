@@ -0,0 +1,302 @@
+//! Deterministic execution of modules by instrumenting their code.
+//!
+//! wasm leaves the bit pattern of NaNs produced by float arithmetic up to the implementation,
+//! so it depends on the host's hardware. Modules loaded into a deterministic runtime have every
+//! such result replaced by the canonical NaN of its type, which takes two scratch locals added
+//! to each function. Shared memories, whose contents depend on thread scheduling, are rejected,
+//! and the clocks and randomness of wasi are replaced by constant stubs when linked.
+use alloc::vec::Vec;
+
+use crate::binary::{self, Reader};
+use crate::error::ParseError;
+#[cfg(feature = "wasi")]
+use crate::{error, function::CallContext, module::Module};
+
+const F32: u8 = 0x7d;
+const F64: u8 = 0x7c;
+
+// f32.const nan, f64.const nan
+const F32_NAN: [u8; 5] = [0x43, 0x00, 0x00, 0xc0, 0x7f];
+const F64_NAN: [u8; 9] = [0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf8, 0x7f];
+
+/// Instruments the given module binary to canonicalize the NaNs produced by float arithmetic.
+///
+/// The binary has to have passed [`binary::validate`].
+pub(crate) fn instrument(bytes: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let mut instrumenter = Instrumenter {
+        out: Vec::with_capacity(bytes.len() + bytes.len() / 2),
+        params: Vec::new(),
+        types: Vec::new(),
+        imported_functions: 0,
+    };
+    let mut reader = binary::read_header(bytes)?;
+    instrumenter.out.extend_from_slice(binary::MAGIC);
+    instrumenter.out.extend_from_slice(binary::VERSION);
+    while let Some(section) = binary::read_section(&mut reader)? {
+        let id = section.id;
+        instrumenter
+            .section(id, section.reader)
+            .map_err(|err| err.in_section(id))?;
+    }
+    Ok(instrumenter.out)
+}
+
+struct Instrumenter {
+    out: Vec<u8>,
+    // the number of parameters of each type
+    params: Vec<u32>,
+    // the type of each function defined in the module
+    types: Vec<u32>,
+    imported_functions: u32,
+}
+
+impl Instrumenter {
+    fn section(&mut self, id: u8, mut reader: Reader<'_>) -> Result<(), ParseError> {
+        let content = reader.remaining();
+        match id {
+            binary::SECTION_TYPE => {
+                for _ in 0..reader.read_u32()? {
+                    if reader.read_u8()? != 0x60 {
+                        return Err(reader.error("expected a function type"));
+                    }
+                    let params = reader.read_u32()?;
+                    reader.read_bytes(params as usize)?;
+                    let results = reader.read_u32()?;
+                    reader.read_bytes(results as usize)?;
+                    self.params.push(params);
+                }
+            }
+            binary::SECTION_IMPORT => {
+                for _ in 0..reader.read_u32()? {
+                    let offset = reader.offset();
+                    let import = reader.read_import()?;
+                    if import.kind == binary::EXTERNAL_FUNCTION {
+                        self.imported_functions += 1;
+                    }
+                    if matches!(import.limits, Some(limits) if limits.shared) {
+                        return Err(shared_memory(offset));
+                    }
+                }
+            }
+            binary::SECTION_FUNCTION => {
+                for _ in 0..reader.read_u32()? {
+                    self.types.push(reader.read_u32()?);
+                }
+            }
+            binary::SECTION_MEMORY => {
+                for _ in 0..reader.read_u32()? {
+                    let offset = reader.offset();
+                    if reader.read_limits()?.shared {
+                        return Err(shared_memory(offset));
+                    }
+                }
+            }
+            binary::SECTION_CODE => {
+                let mut content = Vec::with_capacity(reader.remaining().len() * 2);
+                let count = reader.read_u32()?;
+                binary::write_u32(&mut content, count);
+                for index in 0..count {
+                    let function = self.imported_functions + index;
+                    self.body(index as usize, &mut reader, &mut content)
+                        .map_err(|err| err.in_function(function))?;
+                }
+                binary::write_section(&mut self.out, id, &content);
+                return Ok(());
+            }
+            _ => (),
+        }
+        binary::write_section(&mut self.out, id, content);
+        Ok(())
+    }
+
+    fn body(
+        &self,
+        index: usize,
+        reader: &mut Reader<'_>,
+        out: &mut Vec<u8>,
+    ) -> Result<(), ParseError> {
+        let len = reader.read_u32()?;
+        let mut body = reader.sub_reader(len as usize)?;
+        let mut code = Vec::with_capacity(len as usize * 2);
+
+        let params = self
+            .types
+            .get(index)
+            .and_then(|&ty| self.params.get(ty as usize))
+            .ok_or_else(|| body.error("function without a valid type"))?;
+        let groups = body.read_u32()?;
+        binary::write_u32(&mut code, groups + 2);
+        let locals = body.offset();
+        let mut count = u64::from(*params);
+        for _ in 0..groups {
+            count += u64::from(body.read_u32()?);
+            body.read_u8()?;
+        }
+        code.extend_from_slice(body.since(locals));
+        // the scratch locals follow all others
+        code.extend_from_slice(&[0x01, F32, 0x01, F64]);
+        if count >= u64::from(u32::MAX) {
+            return Err(body.error("too many locals"));
+        }
+        let scratch = count as u32;
+
+        while !body.is_empty() {
+            let instruction = body.read_instruction()?;
+            code.extend_from_slice(instruction.bytes);
+            match instruction.opcode {
+                // f32 rounding, sqrt, arithmetic, min, max and f32.demote_f64
+                0x8d..=0x97 | 0xb6 => canonicalize(scratch, &F32_NAN, 0x5b, &mut code),
+                // f64 rounding, sqrt, arithmetic, min, max and f64.promote_f32
+                0x9b..=0xa5 | 0xbb => canonicalize(scratch + 1, &F64_NAN, 0x61, &mut code),
+                _ => (),
+            }
+        }
+
+        binary::write_u32(out, code.len() as u32);
+        out.extend_from_slice(&code);
+        Ok(())
+    }
+}
+
+fn shared_memory(offset: usize) -> ParseError {
+    ParseError::new(
+        "shared memories can't be executed deterministically",
+        offset,
+    )
+}
+
+// local.tee $scratch, nan, local.get $scratch, local.get $scratch, eq, select
+//
+// which keeps the value if it equals itself, and replaces it by the canonical NaN otherwise
+fn canonicalize(scratch: u32, nan: &[u8], eq: u8, out: &mut Vec<u8>) {
+    out.push(0x22);
+    binary::write_u32(out, scratch);
+    out.extend_from_slice(nan);
+    for _ in 0..2 {
+        out.push(0x20);
+        binary::write_u32(out, scratch);
+    }
+    out.extend_from_slice(&[eq, 0x1b]);
+}
+
+#[cfg(feature = "wasi")]
+pub(crate) const WASI_MODULES: [&str; 2] = ["wasi_snapshot_preview1", "wasi_unstable"];
+
+#[cfg(feature = "wasi")]
+const ERRNO_SUCCESS: u32 = 0;
+#[cfg(feature = "wasi")]
+const ERRNO_FAULT: u32 = 21;
+
+/// Replaces the clocks and randomness of wasi linked into the given module by stubs which
+/// report a time of zero, a resolution of one nanosecond and random bytes that are all zero.
+#[cfg(feature = "wasi")]
+pub(crate) fn link_wasi_stubs(module: &mut Module<'_>) -> error::Result<()> {
+    for &wasi in &WASI_MODULES {
+        ignore_missing(module.link_closure(
+            wasi,
+            "clock_time_get",
+            |ctx: CallContext<'_>, (_id, _precision, time): (u32, u64, u32)| {
+                Ok(write_memory(&ctx, time, 8, |time| {
+                    time.copy_from_slice(&0u64.to_le_bytes())
+                }))
+            },
+        ))?;
+        ignore_missing(module.link_closure(
+            wasi,
+            "clock_res_get",
+            |ctx: CallContext<'_>, (_id, resolution): (u32, u32)| {
+                Ok(write_memory(&ctx, resolution, 8, |resolution| {
+                    resolution.copy_from_slice(&1u64.to_le_bytes())
+                }))
+            },
+        ))?;
+        ignore_missing(module.link_closure(
+            wasi,
+            "random_get",
+            |ctx: CallContext<'_>, (buf, len): (u32, u32)| {
+                Ok(write_memory(&ctx, buf, len, |buf| buf.fill(0)))
+            },
+        ))?;
+    }
+    Ok(())
+}
+
+// modules only import the parts of wasi they use
+#[cfg(feature = "wasi")]
+fn ignore_missing(result: error::Result<()>) -> error::Result<()> {
+    match result {
+        Err(error::Error::FunctionNotFound) => Ok(()),
+        result => result,
+    }
+}
+
+// hands the given range of the linear memory to `write`, returning the wasi errno of the call
+#[cfg(feature = "wasi")]
+fn write_memory(
+    ctx: &CallContext<'_>,
+    offset: u32,
+    len: u32,
+    write: impl FnOnce(&mut [u8]),
+) -> u32 {
+    let memory = ctx.memory_mut();
+    if memory.is_null() {
+        return ERRNO_FAULT;
+    }
+    // SAFETY: no other reference to the memory exists while the host function runs
+    let memory = unsafe { &mut *memory };
+    let end = offset as usize + len as usize;
+    match memory.get_mut(offset as usize..end) {
+        Some(range) => {
+            write(range);
+            ERRNO_SUCCESS
+        }
+        None => ERRNO_FAULT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (module (func (export "f") (param f32) (result f32) (f32.add (local.get 0) (local.get 0))))
+    const ADD: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x01, 0x7d, 0x01,
+        0x7d, 0x03, 0x02, 0x01, 0x00, 0x07, 0x05, 0x01, 0x01, 0x66, 0x00, 0x00, 0x0a, 0x09, 0x01,
+        0x07, 0x00, 0x20, 0x00, 0x20, 0x00, 0x92, 0x0b,
+    ];
+
+    // (module (memory 1 1 shared))
+    const SHARED: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x03, 0x01, 0x01,
+    ];
+
+    #[test]
+    fn test_instrument() {
+        let instrumented = instrument(ADD).unwrap();
+        assert_eq!(binary::validate(&instrumented), Ok(()));
+
+        let mut reader = binary::read_header(&instrumented).unwrap();
+        let mut code = None;
+        while let Some(section) = binary::read_section(&mut reader).unwrap() {
+            if section.id == binary::SECTION_CODE {
+                code = Some(section.reader.remaining());
+            }
+        }
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x01, 0x18,
+            // the scratch locals
+            0x02, 0x01, 0x7d, 0x01, 0x7c,
+            0x20, 0x00, 0x20, 0x00, 0x92,
+            0x22, 0x01, 0x43, 0x00, 0x00, 0xc0, 0x7f, 0x20, 0x01, 0x20, 0x01, 0x5b, 0x1b,
+            0x0b,
+        ];
+        assert_eq!(code, Some(expected));
+    }
+
+    #[test]
+    fn test_instrument_shared_memory() {
+        let err = instrument(SHARED).err().unwrap();
+        assert_eq!(err.section(), Some(binary::SECTION_MEMORY));
+    }
+}
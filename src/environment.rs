@@ -1,24 +1,93 @@
 use alloc::boxed::Box;
-use alloc::rc::Rc;
+/// The reference count of data shared between runtimes, which is atomic with the `sync` feature
+/// enabled.
+#[cfg(not(feature = "sync"))]
+pub(crate) use alloc::rc::Rc as Shared;
+/// The reference count of data shared between runtimes, which is atomic with the `sync` feature
+/// enabled.
+#[cfg(feature = "sync")]
+pub(crate) use alloc::sync::Arc as Shared;
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+#[cfg(not(feature = "sync"))]
+use core::marker::PhantomData;
+#[cfg(feature = "sync")]
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use core::ptr::NonNull;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::Read;
+#[cfg(feature = "std")]
+use std::path::Path;
 
+#[cfg(feature = "std")]
+use crate::binary;
 use crate::error::{Error, Result};
 use crate::module::ParsedModule;
 use crate::runtime::Runtime;
 
+/// Held while wasm3 accesses state of an environment that is shared between its runtimes.
+#[cfg(feature = "sync")]
+pub(crate) struct EnvironmentLock<'env> {
+    owner: &'env AtomicUsize,
+    _guard: std::sync::MutexGuard<'env, ()>,
+}
+/// Held while wasm3 accesses state of an environment that is shared between its runtimes.
+#[cfg(not(feature = "sync"))]
+pub(crate) type EnvironmentLock<'env> = PhantomData<&'env ()>;
+
 #[derive(Debug)]
-struct DropEnvironment(NonNull<ffi::M3Environment>);
+struct DropEnvironment {
+    raw: NonNull<ffi::M3Environment>,
+    // guards the function types and released code pages wasm3 keeps in the environment
+    #[cfg(feature = "sync")]
+    lock: std::sync::Mutex<()>,
+    // the thread holding the lock, see `current_thread`
+    #[cfg(feature = "sync")]
+    owner: AtomicUsize,
+}
+
+#[cfg(feature = "sync")]
+impl Drop for EnvironmentLock<'_> {
+    fn drop(&mut self) {
+        // cleared before the guard releases the lock
+        self.owner.store(0, Ordering::Relaxed);
+    }
+}
+
+// identifies the current thread by the address of a thread local, which is unique among the
+// threads alive and never zero
+#[cfg(feature = "sync")]
+fn current_thread() -> usize {
+    std::thread_local!(static MARKER: u8 = const { 0 });
+    MARKER.with(|marker| marker as *const u8 as usize)
+}
+
+// SAFETY: all accesses to the environment's mutable state are done while holding its lock
+#[cfg(feature = "sync")]
+unsafe impl Send for DropEnvironment {}
+#[cfg(feature = "sync")]
+unsafe impl Sync for DropEnvironment {}
 
 impl Drop for DropEnvironment {
     fn drop(&mut self) {
-        unsafe { ffi::m3_FreeEnvironment(self.0.as_ptr()) };
+        unsafe { ffi::m3_FreeEnvironment(self.raw.as_ptr()) };
     }
 }
 
 /// An environment is required to construct [`Runtime`]s from.
+///
+/// With the `sync` feature enabled, environments can be shared between threads. Runtimes of
+/// the same environment then take turns parsing, loading, linking and looking up functions, as
+/// wasm3 keeps state shared between them in the environment, while calls into wasm run in
+/// parallel. For calls not to compile code, modules are compiled as a whole when the first of
+/// their functions is looked up, see [`RuntimeBuilder::compile_eagerly`].
+///
+/// [`RuntimeBuilder::compile_eagerly`]: crate::RuntimeBuilder::compile_eagerly
 #[derive(Debug, Clone)]
-pub struct Environment(Rc<DropEnvironment>);
+pub struct Environment(Shared<DropEnvironment>);
 
 impl Environment {
     /// Creates a new environment.
@@ -30,10 +99,18 @@ impl Environment {
     pub fn new() -> Result<Self> {
         unsafe { NonNull::new(ffi::m3_NewEnvironment()) }
             .ok_or_else(Error::malloc_error)
-            .map(|raw| Environment(Rc::new(DropEnvironment(raw))))
+            .map(|raw| {
+                Environment(Shared::new(DropEnvironment {
+                    raw,
+                    #[cfg(feature = "sync")]
+                    lock: std::sync::Mutex::new(()),
+                    #[cfg(feature = "sync")]
+                    owner: AtomicUsize::new(0),
+                }))
+            })
     }
 
-    /// Creates a new runtime with the given stack size in slots.
+    /// Creates a new runtime with the given stack size in bytes.
     ///
     /// # Errors
     ///
@@ -49,16 +126,85 @@ impl Environment {
         ParsedModule::parse(self, bytes)
     }
 
+    /// Reads and parses the wasm module in the file at the given path.
+    ///
+    /// # Errors
+    ///
+    /// This function will error like [`Environment::parse_module_reader`], with the error
+    /// wrapped in [`Error::File`] together with the path.
+    #[cfg(feature = "std")]
+    pub fn parse_module_file<P: AsRef<Path>>(&self, path: P) -> Result<ParsedModule> {
+        let path = path.as_ref();
+        File::open(path)
+            .map_err(Error::Io)
+            .and_then(|file| self.parse_module_reader(file))
+            .map_err(|err| Error::File(path.to_owned(), Box::new(err)))
+    }
+
+    /// Reads the given reader to its end and parses the wasm module read.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`Error::Io`] if reading fails, and otherwise error like
+    /// [`ParsedModule::parse`]. Data that doesn't start with the wasm magic number and version
+    /// is rejected without reading it any further.
+    #[cfg(feature = "std")]
+    pub fn parse_module_reader<R: Read>(&self, mut reader: R) -> Result<ParsedModule> {
+        let mut data = Vec::new();
+        // text format modules are detected by their first bytes already
+        reader
+            .by_ref()
+            .take(16)
+            .read_to_end(&mut data)
+            .map_err(Error::Io)?;
+        binary::read_header(&data).map_err(Error::Parse)?;
+        reader.read_to_end(&mut data).map_err(Error::Io)?;
+        self.parse_module(data)
+    }
+
     #[inline]
     pub(crate) fn as_ptr(&self) -> ffi::IM3Environment {
-        (self.0).0.as_ptr()
+        self.0.raw.as_ptr()
+    }
+
+    /// Locks the environment for wasm3 calls that access its shared state, which is anything
+    /// that may compile code or parse function types.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`Error::EnvironmentLocked`] instead of deadlocking if the
+    /// current thread holds the lock already, which happens if a host function called by a
+    /// start function uses the environment.
+    #[inline]
+    pub(crate) fn lock(&self) -> Result<EnvironmentLock<'_>> {
+        #[cfg(feature = "sync")]
+        {
+            let thread = current_thread();
+            // only this thread ever stores its own id, so a stale read can't match it
+            if self.0.owner.load(Ordering::Relaxed) == thread {
+                return Err(Error::EnvironmentLocked);
+            }
+            // host panics are caught before they could poison the lock
+            let guard = self
+                .0
+                .lock
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            self.0.owner.store(thread, Ordering::Relaxed);
+            Ok(EnvironmentLock {
+                owner: &self.0.owner,
+                _guard: guard,
+            })
+        }
+        #[cfg(not(feature = "sync"))]
+        Ok(PhantomData)
     }
 }
 
 impl core::cmp::Eq for Environment {}
 impl core::cmp::PartialEq for Environment {
     fn eq(&self, &Environment(ref other): &Environment) -> bool {
-        alloc::rc::Rc::ptr_eq(&self.0, other)
+        Shared::ptr_eq(&self.0, other)
     }
 }
 
@@ -1,16 +1,44 @@
 //! Error related functionality of wasm3.
+#[cfg(feature = "std")]
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::cmp;
 use core::fmt;
+use core::ptr;
 
-use crate::utils::cstr_to_str;
+#[cfg(any(feature = "std", feature = "wasi"))]
+use crate::runtime::RuntimeState;
+use crate::utils::{bytes_till_null, cstr_to_str};
 
 /// Result alias that uses [`Error`].
 pub type Result<T> = core::result::Result<T, Error>;
 /// Result alias that uses [`Trap`].
 pub type TrappedResult<T> = core::result::Result<T, Trap>;
+/// Result alias that uses [`HostError`].
+pub type HostResult<T> = core::result::Result<T, HostError>;
+
+// returned to wasm3 by host functions that failed with a custom error, the error itself is stashed
+// in the runtime's state until the call into wasm returns
+#[cfg(feature = "std")]
+static HOST_ERROR: [u8; 11] = *b"host error\0";
+// returned to wasm3 by host functions that panicked, the payload is stashed like a custom error
+#[cfg(feature = "std")]
+static HOST_PANIC: [u8; 23] = *b"host function panicked\0";
+
+/// Stashes the payload of a panicking host function in the runtime's state.
+#[cfg(feature = "std")]
+pub(crate) unsafe fn stash_host_panic(
+    runtime: ffi::IM3Runtime,
+    payload: Box<dyn core::any::Any + Send>,
+) -> ffi::M3Result {
+    RuntimeState::from_raw(runtime).host_panic = Some(payload);
+    HOST_PANIC.as_ptr().cast()
+}
 
 /// A wasm trap.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Trap {
     /// Out of bounds memory access
     OutOfBoundsMemoryAccess,
@@ -32,8 +60,23 @@ pub enum Trap {
     Unreachable,
     /// Stack overflow
     StackOverflow,
+    /// The runtime ran out of fuel while executing a metered module
+    OutOfFuel,
+    /// The call has been interrupted through an [`InterruptHandle`]
+    ///
+    /// [`InterruptHandle`]: crate::InterruptHandle
+    Interrupted,
+    /// The call has been cancelled by the runtime's yield callback, which requires the
+    /// `yield-callback` feature
+    Cancelled,
 }
 
+// wasm3 has no results for running out of fuel, being interrupted or cancelled, so these traps
+// are identified by these strings instead
+static OUT_OF_FUEL: [u8; 19] = *b"[trap] out of fuel\0";
+static INTERRUPTED: [u8; 19] = *b"[trap] interrupted\0";
+static CANCELLED: [u8; 17] = *b"[trap] cancelled\0";
+
 impl Trap {
     #[doc(hidden)]
     pub fn as_ptr(self) -> ffi::M3Result {
@@ -49,14 +92,40 @@ impl Trap {
                 Trap::Abort => ffi::m3Err_trapAbort,
                 Trap::Unreachable => ffi::m3Err_trapUnreachable,
                 Trap::StackOverflow => ffi::m3Err_trapStackOverflow,
+                Trap::OutOfFuel => OUT_OF_FUEL.as_ptr().cast(),
+                Trap::Interrupted => INTERRUPTED.as_ptr().cast(),
+                Trap::Cancelled => CANCELLED.as_ptr().cast(),
             }
         }
     }
 }
 
+impl Trap {
+    fn from_ptr(ptr: ffi::M3Result) -> Option<Trap> {
+        [
+            Trap::OutOfBoundsMemoryAccess,
+            Trap::DivisionByZero,
+            Trap::IntegerOverflow,
+            Trap::IntegerConversion,
+            Trap::IndirectCallTypeMismatch,
+            Trap::TableIndexOutOfRange,
+            Trap::Exit,
+            Trap::Abort,
+            Trap::Unreachable,
+            Trap::StackOverflow,
+            Trap::OutOfFuel,
+            Trap::Interrupted,
+            Trap::Cancelled,
+        ]
+        .iter()
+        .copied()
+        .find(|trap| trap.as_ptr() == ptr)
+    }
+}
+
 impl cmp::PartialEq<Wasm3Error> for Trap {
-    fn eq(&self, &Wasm3Error(err): &Wasm3Error) -> bool {
-        self.as_ptr() == err
+    fn eq(&self, err: &Wasm3Error) -> bool {
+        self.as_ptr() == err.raw
     }
 }
 
@@ -68,20 +137,239 @@ impl fmt::Display for Trap {
     }
 }
 
+/// The kind of an [`Error`], classifying the error's cause.
+///
+/// Like [`Error`], this depends on features for some of its variants.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The module binary is malformed or uses opcodes that are unknown or not allowed, or a
+    /// serialized snapshot is malformed.
+    Parse,
+    /// Linking or looking up functions and modules failed, for example because an import is
+    /// missing or a signature did not match.
+    Link,
+    /// A function could not be compiled.
+    Compile,
+    /// The runtime ran out of a resource, like memory or stack space.
+    Resource,
+    /// An operation on the runtime was invalid, like accessing a global of the wrong type.
+    Runtime,
+    /// The wasm code trapped.
+    Trap(Trap),
+    /// A host function failed with a custom error.
+    #[cfg(feature = "std")]
+    Host,
+    /// A host function panicked.
+    #[cfg(feature = "std")]
+    HostPanic,
+    /// The wasm program exited via WASI's `proc_exit`.
+    #[cfg(feature = "wasi")]
+    Exit,
+    /// Reading a module failed.
+    #[cfg(feature = "std")]
+    Io,
+    /// An error wasm3 did not classify.
+    Other,
+}
+
+/// Error returned by host functions linked via [`Module::link_fallible_closure`].
+///
+/// This is either a [`Trap`] or, with the `std` feature enabled, a custom error which will be
+/// returned from the call into wasm as [`Error::Host`].
+///
+/// [`Module::link_fallible_closure`]: crate::Module::link_fallible_closure
+#[derive(Debug)]
+pub struct HostError(HostErrorKind);
+
+#[derive(Debug)]
+enum HostErrorKind {
+    Trap(Trap),
+    #[cfg(feature = "std")]
+    Custom(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl HostError {
+    /// Creates a host error from a custom error.
+    #[cfg(feature = "std")]
+    pub fn new<E>(error: E) -> Self
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        HostError(HostErrorKind::Custom(error.into()))
+    }
+
+    /// The trap this error represents, if it isn't a custom error.
+    pub fn trap(&self) -> Option<Trap> {
+        match self.0 {
+            HostErrorKind::Trap(trap) => Some(trap),
+            #[cfg(feature = "std")]
+            HostErrorKind::Custom(_) => None,
+        }
+    }
+
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    pub(crate) fn into_ffi_res(self, runtime: ffi::IM3Runtime) -> ffi::M3Result {
+        match self.0 {
+            HostErrorKind::Trap(trap) => trap.as_ptr(),
+            #[cfg(feature = "std")]
+            HostErrorKind::Custom(err) => {
+                unsafe { RuntimeState::from_raw(runtime) }.host_error = Some(err);
+                HOST_ERROR.as_ptr().cast()
+            }
+        }
+    }
+}
+
+impl From<Trap> for HostError {
+    fn from(trap: Trap) -> Self {
+        HostError(HostErrorKind::Trap(trap))
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for HostError {}
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            HostErrorKind::Trap(trap) => fmt::Display::fmt(trap, f),
+            #[cfg(feature = "std")]
+            HostErrorKind::Custom(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
 /// Error returned by wasm3.
-#[derive(Copy, Clone, PartialEq, Eq)]
-pub struct Wasm3Error(*const cty::c_char);
+///
+/// As it carries the details recorded when it occurred, this error isn't `Copy`, but cloning
+/// it is cheap.
+#[derive(Clone)]
+pub struct Wasm3Error {
+    raw: *const cty::c_char,
+    // shared so that the error stays cheap to clone
+    info: Option<Arc<ErrorInfo>>,
+    backtrace: Option<Arc<Backtrace>>,
+}
+
+// SAFETY: the raw result points to one of wasm3's static error strings
+unsafe impl Send for Wasm3Error {}
+unsafe impl Sync for Wasm3Error {}
 
 impl Wasm3Error {
     /// Check whether this error is the specified trap.
-    pub fn is_trap(self, trap: Trap) -> bool {
-        trap.as_ptr() == self.0
+    pub fn is_trap(&self, trap: Trap) -> bool {
+        trap.as_ptr() == self.raw
+    }
+
+    /// The kind of this error.
+    ///
+    /// Note that a wasm stack overflow is classified as [`ErrorKind::Resource`] rather than as
+    /// [`Trap::StackOverflow`].
+    pub fn kind(&self) -> ErrorKind {
+        let raw = self.raw;
+        if raw == unsafe { ffi::m3Err_trapStackOverflow } {
+            return ErrorKind::Resource;
+        }
+        if let Some(trap) = Trap::from_ptr(raw) {
+            return ErrorKind::Trap(trap);
+        }
+        #[cfg(feature = "std")]
+        {
+            if raw == HOST_ERROR.as_ptr().cast() {
+                return ErrorKind::Host;
+            } else if raw == HOST_PANIC.as_ptr().cast() {
+                return ErrorKind::HostPanic;
+            }
+        }
+        let (parse, link, compile, resource, runtime) = unsafe {
+            (
+                [
+                    ffi::m3Err_incompatibleWasmVersion,
+                    ffi::m3Err_wasmMalformed,
+                    ffi::m3Err_misorderedWasmSection,
+                    ffi::m3Err_wasmUnderrun,
+                    ffi::m3Err_wasmOverrun,
+                    ffi::m3Err_wasmMissingInitExpr,
+                    ffi::m3Err_lebOverflow,
+                    ffi::m3Err_missingUTF8,
+                    ffi::m3Err_wasmSectionUnderrun,
+                    ffi::m3Err_wasmSectionOverrun,
+                    ffi::m3Err_invalidTypeId,
+                    ffi::m3Err_tooManyMemorySections,
+                    ffi::m3Err_tooManyArgsRets,
+                    ffi::m3Err_unknownOpcode,
+                    ffi::m3Err_restrictedOpcode,
+                ],
+                [
+                    ffi::m3Err_moduleNotLinked,
+                    ffi::m3Err_moduleAlreadyLinked,
+                    ffi::m3Err_functionLookupFailed,
+                    ffi::m3Err_functionImportMissing,
+                    ffi::m3Err_malformedFunctionSignature,
+                ],
+                [
+                    ffi::m3Err_noCompiler,
+                    ffi::m3Err_functionStackUnderrun,
+                    ffi::m3Err_settingImmutableGlobal,
+                    ffi::m3Err_typeMismatch,
+                    ffi::m3Err_typeCountMismatch,
+                    ffi::m3Err_missingCompiledCode,
+                ],
+                [
+                    ffi::m3Err_mallocFailed,
+                    ffi::m3Err_mallocFailedCodePage,
+                    ffi::m3Err_functionStackOverflow,
+                    ffi::m3Err_wasmMemoryOverflow,
+                ],
+                [
+                    ffi::m3Err_globalMemoryNotAllocated,
+                    ffi::m3Err_globaIndexOutOfBounds,
+                    ffi::m3Err_argumentCountMismatch,
+                    ffi::m3Err_argumentTypeMismatch,
+                    ffi::m3Err_globalLookupFailed,
+                    ffi::m3Err_globalTypeMismatch,
+                    ffi::m3Err_globalNotMutable,
+                ],
+            )
+        };
+        if parse.contains(&raw) {
+            ErrorKind::Parse
+        } else if link.contains(&raw) {
+            ErrorKind::Link
+        } else if compile.contains(&raw) {
+            ErrorKind::Compile
+        } else if resource.contains(&raw) {
+            ErrorKind::Resource
+        } else if runtime.contains(&raw) {
+            ErrorKind::Runtime
+        } else {
+            ErrorKind::Other
+        }
+    }
+
+    /// The detailed information the runtime recorded when this error occurred, if any.
+    pub fn info(&self) -> Option<&ErrorInfo> {
+        self.info.as_deref()
+    }
+
+    /// The wasm backtrace recorded when this error occurred, if any.
+    ///
+    /// Backtraces are only recorded if the `backtrace` feature is enabled.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace.as_deref()
+    }
+}
+
+impl cmp::Eq for Wasm3Error {}
+impl cmp::PartialEq for Wasm3Error {
+    fn eq(&self, other: &Wasm3Error) -> bool {
+        self.raw == other.raw
     }
 }
 
 impl cmp::PartialEq<Trap> for Wasm3Error {
     fn eq(&self, trap: &Trap) -> bool {
-        trap.as_ptr() == self.0
+        trap.as_ptr() == self.raw
     }
 }
 
@@ -89,20 +377,336 @@ impl cmp::PartialEq<Trap> for Wasm3Error {
 impl std::error::Error for Wasm3Error {}
 impl fmt::Debug for Wasm3Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(unsafe { cstr_to_str(self.0) }, f)
+        fmt::Debug::fmt(unsafe { cstr_to_str(self.raw) }, f)
     }
 }
 impl fmt::Display for Wasm3Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(unsafe { cstr_to_str(self.0) }, f)
+        fmt::Display::fmt(unsafe { cstr_to_str(self.raw) }, f)?;
+        match self.info() {
+            Some(info) => fmt::Display::fmt(info, f),
+            None => Ok(()),
+        }
     }
 }
 
-/// Error returned by wasm3-rs.
+/// Detailed information the runtime recorded about an error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorInfo {
+    message: Option<String>,
+    function: Option<String>,
+    file: Option<String>,
+    line: u32,
+}
+
+impl ErrorInfo {
+    /// Takes the error information the given runtime recorded, if it belongs to the given result.
+    fn capture(runtime: ffi::IM3Runtime, result: ffi::M3Result) -> Option<Self> {
+        let mut raw = core::mem::MaybeUninit::<ffi::M3ErrorInfo>::zeroed();
+        let raw = unsafe {
+            ffi::m3_GetErrorInfo(runtime, raw.as_mut_ptr());
+            raw.assume_init()
+        };
+        if raw.result != result {
+            return None;
+        }
+        let function = if raw.function.is_null() {
+            ptr::null()
+        } else {
+            unsafe { ffi::m3_GetFunctionName(raw.function) }
+        };
+        unsafe {
+            Some(ErrorInfo {
+                message: owned_cstr(raw.message),
+                function: owned_cstr(function),
+                file: owned_cstr(raw.file),
+                line: raw.line,
+            })
+        }
+    }
+
+    /// The detailed error message.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    /// The name of the function the error occurred in.
+    pub fn function_name(&self) -> Option<&str> {
+        self.function.as_deref()
+    }
+
+    /// The wasm3 source file that raised the error.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// The line in the wasm3 source file that raised the error.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+}
+
+impl fmt::Display for ErrorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(message) = self.message() {
+            write!(f, ": {}", message)?;
+        }
+        if let Some(function) = self.function_name() {
+            write!(f, " (in function `{}`)", function)?;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn owned_cstr(ptr: *const cty::c_char) -> Option<String> {
+    let bytes = bytes_till_null(ptr);
+    if bytes.is_empty() {
+        None
+    } else {
+        Some(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// A single frame of a [`Backtrace`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    function_index: Option<u32>,
+    function_name: Option<String>,
+    module_offset: u32,
+}
+
+impl Frame {
+    /// The index of the function this frame belongs to in its module's function index space.
+    pub fn function_index(&self) -> Option<u32> {
+        self.function_index
+    }
+
+    /// The name of the function this frame belongs to.
+    pub fn function_name(&self) -> Option<&str> {
+        self.function_name.as_deref()
+    }
+
+    /// The byte offset into the module's binary this frame was executing at.
+    pub fn module_offset(&self) -> u32 {
+        self.module_offset
+    }
+
+    unsafe fn from_raw(raw: &ffi::M3BacktraceFrame) -> Self {
+        let function = raw.function;
+        let (function_index, function_name) = if function.is_null() {
+            (None, None)
+        } else {
+            let module = (*function).module;
+            let index = if module.is_null() {
+                None
+            } else {
+                Some(function.offset_from((*module).functions) as u32)
+            };
+            (index, owned_cstr(ffi::m3_GetFunctionName(function)))
+        };
+        Frame {
+            function_index,
+            function_name,
+            module_offset: raw.moduleOffset,
+        }
+    }
+}
+
+/// A wasm backtrace, listing the innermost frame first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Backtrace {
+    frames: Vec<Frame>,
+}
+
+impl Backtrace {
+    /// Captures the backtrace wasm3 recorded for the last failed call of the given runtime.
+    fn capture(runtime: ffi::IM3Runtime) -> Option<Self> {
+        let info = unsafe { ffi::m3_GetBacktrace(runtime) };
+        if info.is_null() {
+            return None;
+        }
+        let mut frames = Vec::new();
+        let mut frame = unsafe { (*info).frames };
+        while let Some(raw) = unsafe { frame.as_ref() } {
+            frames.push(unsafe { Frame::from_raw(raw) });
+            frame = raw.next;
+        }
+        Some(Backtrace { frames })
+    }
+
+    /// The frames of this backtrace, innermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>4}: {}",
+                i,
+                frame.function_name().unwrap_or("<unknown>")
+            )?;
+            match frame.function_index {
+                Some(index) => writeln!(
+                    f,
+                    "             at <wasm function {}>:{:#x}",
+                    index, frame.module_offset
+                )?,
+                None => writeln!(f, "             at {:#x}", frame.module_offset)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Error locating where in a module's binary parsing failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    message: &'static str,
+    offset: usize,
+    section: Option<u8>,
+    function: Option<u32>,
+}
+
+impl ParseError {
+    pub(crate) fn new(message: &'static str, offset: usize) -> Self {
+        ParseError {
+            message,
+            offset,
+            section: None,
+            function: None,
+        }
+    }
+
+    pub(crate) fn in_section(mut self, id: u8) -> Self {
+        self.section = Some(id);
+        self
+    }
+
+    pub(crate) fn in_function(mut self, index: u32) -> Self {
+        self.function = Some(index);
+        self
+    }
+
+    /// A description of what is malformed.
+    pub fn message(&self) -> &str {
+        self.message
+    }
+
+    /// The byte offset into the binary at which the failure occurred.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The id of the section the failure occurred in, if it occurred inside a section.
+    pub fn section(&self) -> Option<u8> {
+        self.section
+    }
+
+    /// The index of the function whose body was being decoded, if any.
+    pub fn function(&self) -> Option<u32> {
+        self.function
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {:#x}", self.message, self.offset)?;
+        if let Some(id) = self.section {
+            write!(f, " in the {} section", crate::binary::section_name(id))?;
+        }
+        if let Some(index) = self.function {
+            write!(f, " of function {}", index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error a host function linked with [`Module::link_closure_with_quota`] fails with once a
+/// call would exceed its quota, returned from the call into wasm as [`Error::Host`].
+///
+/// [`Module::link_closure_with_quota`]: crate::Module::link_closure_with_quota
+#[cfg(feature = "std")]
 #[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    module: String,
+    function: String,
+    cost: u64,
+    remaining: u64,
+}
+
+#[cfg(feature = "std")]
+impl QuotaExceeded {
+    pub(crate) fn new(module: &str, function: &str, cost: u64, remaining: u64) -> Self {
+        QuotaExceeded {
+            module: module.into(),
+            function: function.into(),
+            cost,
+            remaining,
+        }
+    }
+
+    /// The module name the host function was linked under.
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    /// The name the host function was linked under.
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+
+    /// The cost of a call to the host function.
+    pub fn cost(&self) -> u64 {
+        self.cost
+    }
+
+    /// The budget that remained in the quota when the call was refused.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for QuotaExceeded {}
+#[cfg(feature = "std")]
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "call to host function {}.{} exceeds its quota, costing {} with {} remaining",
+            self.module, self.function, self.cost, self.remaining
+        )
+    }
+}
+
+/// Error returned by wasm3-rs.
+///
+/// Some variants only exist with certain features enabled, so matches on this enum need a
+/// wildcard arm. This error isn't `Clone`, as the errors of host functions and the payloads of
+/// their panics it carries can't be cloned.
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// An error originating from wasm3 itself may or may not be a trap.
     Wasm3(Wasm3Error),
+    /// The module binary is malformed.
+    Parse(ParseError),
+    /// A host function failed with a custom [`HostError`].
+    #[cfg(feature = "std")]
+    Host(Box<dyn std::error::Error + Send + Sync>),
+    /// The wasm program exited via WASI's `proc_exit` with the given exit code.
+    #[cfg(feature = "wasi")]
+    Exit(i32),
+    /// A host function panicked, carrying the panic's payload.
+    /// The panic can be continued with [`std::panic::resume_unwind`].
+    #[cfg(feature = "std")]
+    HostPanic(Box<dyn core::any::Any + Send>),
     /// A function has been found but its signature didn't match.
     InvalidFunctionSignature,
     /// The specified function could not be found.
@@ -111,6 +715,33 @@ pub enum Error {
     ModuleNotFound,
     /// The modules environment did not match the runtime's environment.
     ModuleLoadEnvMismatch,
+    /// The environment is in use by the current thread already, as a host function called by a
+    /// start function tried to parse, load or link a module of the same environment.
+    #[cfg(feature = "sync")]
+    EnvironmentLocked,
+    /// A [`RuntimeBuilder`] has been configured inconsistently, for the given reason.
+    ///
+    /// [`RuntimeBuilder`]: crate::RuntimeBuilder
+    InvalidRuntimeConfig(&'static str),
+    /// The module's memory is larger than the runtime's memory limit.
+    MemoryLimitExceeded,
+    /// One of the module's tables is larger than the runtime's table limit.
+    TableLimitExceeded,
+    /// A [`Snapshot`] has been taken of a runtime with other modules loaded than the one it is
+    /// restored into.
+    ///
+    /// [`Snapshot`]: crate::Snapshot
+    SnapshotMismatch,
+    /// Serialized [`Snapshot`] data is malformed, for the given reason.
+    ///
+    /// [`Snapshot`]: crate::Snapshot
+    InvalidSnapshot(&'static str),
+    /// Reading a module failed.
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+    /// Loading the module file at the given path failed with the given error.
+    #[cfg(feature = "std")]
+    File(std::path::PathBuf, Box<Error>),
 }
 
 impl Error {
@@ -120,21 +751,186 @@ impl Error {
         } else if unsafe { ptr == ffi::m3Err_functionLookupFailed } {
             Err(Error::FunctionNotFound)
         } else {
-            Err(Error::Wasm3(Wasm3Error(ptr)))
+            Err(Error::Wasm3(Wasm3Error::from_raw(ptr)))
+        }
+    }
+
+    /// Like [`Error::from_ffi_res`], but additionally captures the error information the given
+    /// runtime recorded for the failed operation.
+    pub(crate) fn from_runtime_res(runtime: ffi::IM3Runtime, ptr: ffi::M3Result) -> Result<()> {
+        Error::from_ffi_res(ptr).map_err(|err| match err {
+            Error::Wasm3(mut err) => {
+                err.info = ErrorInfo::capture(runtime, ptr).map(Arc::new);
+                Error::Wasm3(err)
+            }
+            err => err,
+        })
+    }
+
+    /// Like [`Error::from_runtime_res`], but additionally captures the backtrace of the failed
+    /// call and picks up errors of host functions that were stashed in the runtime.
+    pub(crate) fn from_call_res(runtime: ffi::IM3Runtime, ptr: ffi::M3Result) -> Result<()> {
+        #[cfg(feature = "std")]
+        {
+            let state = unsafe { RuntimeState::from_raw(runtime) };
+            if ptr == HOST_ERROR.as_ptr().cast() {
+                if let Some(err) = state.host_error.take() {
+                    return Err(Error::Host(err));
+                }
+            } else if ptr == HOST_PANIC.as_ptr().cast() {
+                if let Some(payload) = state.host_panic.take() {
+                    return Err(Error::HostPanic(payload));
+                }
+            }
+        }
+        #[cfg(feature = "wasi")]
+        {
+            // only exits through wasi carry an exit code, host functions may trap with
+            // `Trap::Exit` as well
+            if ptr == unsafe { ffi::m3Err_trapExit } {
+                if let Some(code) = unsafe { RuntimeState::from_raw(runtime) }.exit_code.take() {
+                    return Err(Error::Exit(code));
+                }
+            }
         }
+        Error::from_runtime_res(runtime, ptr).map_err(|err| match err {
+            Error::Wasm3(mut err) => {
+                err.backtrace = Backtrace::capture(runtime).map(Arc::new);
+                Error::Wasm3(err)
+            }
+            err => err,
+        })
     }
 
     pub(crate) fn malloc_error() -> Self {
-        Error::Wasm3(Wasm3Error(unsafe { ffi::m3Err_mallocFailed }))
+        Error::Wasm3(Wasm3Error::from_raw(unsafe { ffi::m3Err_mallocFailed }))
+    }
+
+    /// The kind of this error.
+    ///
+    /// Note that a wasm stack overflow is classified as [`ErrorKind::Resource`] rather than as
+    /// [`Trap::StackOverflow`].
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Wasm3(err) => err.kind(),
+            Error::Parse(_) | Error::InvalidSnapshot(_) => ErrorKind::Parse,
+            #[cfg(feature = "std")]
+            Error::Io(_) => ErrorKind::Io,
+            #[cfg(feature = "std")]
+            Error::File(_, err) => err.kind(),
+            #[cfg(feature = "std")]
+            Error::Host(_) => ErrorKind::Host,
+            #[cfg(feature = "wasi")]
+            Error::Exit(_) => ErrorKind::Exit,
+            #[cfg(feature = "std")]
+            Error::HostPanic(_) => ErrorKind::HostPanic,
+            Error::InvalidFunctionSignature
+            | Error::FunctionNotFound
+            | Error::ModuleNotFound
+            | Error::ModuleLoadEnvMismatch => ErrorKind::Link,
+            Error::InvalidRuntimeConfig(_) | Error::SnapshotMismatch => ErrorKind::Runtime,
+            #[cfg(feature = "sync")]
+            Error::EnvironmentLocked => ErrorKind::Runtime,
+            Error::MemoryLimitExceeded | Error::TableLimitExceeded => ErrorKind::Resource,
+        }
+    }
+
+    /// The detailed information the runtime recorded when this error occurred, if any.
+    pub fn info(&self) -> Option<&ErrorInfo> {
+        match self {
+            Error::Wasm3(err) => err.info(),
+            _ => None,
+        }
+    }
+
+    /// The wasm backtrace recorded when this error occurred, if any.
+    ///
+    /// Backtraces are only recorded for errors returned by calls into wasm and only if the
+    /// `backtrace` feature is enabled.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        match self {
+            Error::Wasm3(err) => err.backtrace(),
+            _ => None,
+        }
+    }
+}
+
+impl Wasm3Error {
+    fn from_raw(raw: ffi::M3Result) -> Self {
+        Wasm3Error {
+            raw,
+            info: None,
+            backtrace: None,
+        }
+    }
+}
+
+impl cmp::Eq for Error {}
+impl cmp::PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        match (self, other) {
+            (Error::Wasm3(this), Error::Wasm3(other)) => this == other,
+            (Error::Parse(this), Error::Parse(other)) => this == other,
+            (Error::InvalidRuntimeConfig(this), Error::InvalidRuntimeConfig(other)) => {
+                this == other
+            }
+            (Error::InvalidSnapshot(this), Error::InvalidSnapshot(other)) => this == other,
+            // io errors can't be compared either, so only their kinds are
+            #[cfg(feature = "std")]
+            (Error::Io(this), Error::Io(other)) => this.kind() == other.kind(),
+            #[cfg(feature = "std")]
+            (Error::File(this_path, this), Error::File(other_path, other)) => {
+                this_path == other_path && this == other
+            }
+            #[cfg(feature = "wasi")]
+            (Error::Exit(this), Error::Exit(other)) => this == other,
+            // custom errors can't be compared, so only consider the very same error to be equal
+            #[cfg(feature = "std")]
+            (Error::Host(this), Error::Host(other)) => core::ptr::eq(
+                &**this as *const _ as *const u8,
+                &**other as *const _ as *const u8,
+            ),
+            #[cfg(feature = "std")]
+            (Error::HostPanic(this), Error::HostPanic(other)) => core::ptr::eq(
+                &**this as *const _ as *const u8,
+                &**other as *const _ as *const u8,
+            ),
+            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
+        }
     }
 }
 
 #[cfg(feature = "std")]
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(err) => Some(err),
+            Error::Host(err) => Some(&**err),
+            Error::Io(err) => Some(err),
+            Error::File(_, err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::Wasm3(err) => fmt::Display::fmt(err, f),
+            Error::Parse(err) => fmt::Display::fmt(err, f),
+            #[cfg(feature = "std")]
+            Error::Host(err) => fmt::Display::fmt(err, f),
+            #[cfg(feature = "wasi")]
+            Error::Exit(code) => write!(f, "the wasm program exited with code {}", code),
+            #[cfg(feature = "std")]
+            Error::HostPanic(payload) => {
+                write!(f, "a host function panicked")?;
+                if let Some(message) = payload.downcast_ref::<&str>() {
+                    write!(f, ": {}", message)?;
+                } else if let Some(message) = payload.downcast_ref::<alloc::string::String>() {
+                    write!(f, ": {}", message)?;
+                }
+                Ok(())
+            }
             Error::InvalidFunctionSignature => {
                 write!(f, "the found function had an unexpected signature")
             }
@@ -143,6 +939,32 @@ impl fmt::Display for Error {
             Error::ModuleLoadEnvMismatch => {
                 write!(f, "the module and runtime environments were not the same")
             }
+            #[cfg(feature = "sync")]
+            Error::EnvironmentLocked => {
+                write!(f, "the environment is in use by the current thread already")
+            }
+            Error::InvalidRuntimeConfig(reason) => {
+                write!(f, "invalid runtime configuration: {}", reason)
+            }
+            Error::MemoryLimitExceeded => {
+                write!(f, "the module's memory exceeds the runtime's memory limit")
+            }
+            Error::TableLimitExceeded => {
+                write!(f, "the module's table exceeds the runtime's table limit")
+            }
+            Error::SnapshotMismatch => {
+                write!(
+                    f,
+                    "the snapshot was taken of a runtime with other modules loaded"
+                )
+            }
+            Error::InvalidSnapshot(reason) => write!(f, "invalid snapshot: {}", reason),
+            #[cfg(feature = "std")]
+            Error::Io(err) => fmt::Display::fmt(err, f),
+            #[cfg(feature = "std")]
+            Error::File(path, err) => {
+                write!(f, "failed to load module {}: {}", path.display(), err)
+            }
         }
     }
 }
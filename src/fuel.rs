@@ -0,0 +1,529 @@
+//! Fuel metering and interrupt checks by instrumenting the code of modules.
+//!
+//! A metered module counts down an exported `i64` global at the entry of every function and
+//! loop by the number of instructions up to the next such point. Once the global drops below
+//! zero, an imported hook is called which refills it with a slice of the runtime's fuel or
+//! traps with [`Trap::OutOfFuel`] if the runtime ran out of it.
+//!
+//! Interruptible modules, which metered modules are as well, check an exported `i32` global at
+//! the same points and trap with `unreachable` if it is set, which interrupt handles do to
+//! interrupt a call. Unlike the hook, this adds no import, so function indices stay the same in
+//! modules that aren't metered.
+use alloc::vec::Vec;
+use core::ptr;
+
+use crate::binary::{self, Instruction, Reader};
+use crate::error::{ParseError, Trap};
+use crate::runtime::RuntimeState;
+
+pub(crate) const HOOK_MODULE: &[u8] = b"wasm3_rs\0";
+pub(crate) const HOOK_FUNCTION: &[u8] = b"refuel\0";
+pub(crate) const HOOK_SIGNATURE: &[u8] = b"v()\0";
+pub(crate) const FUEL_GLOBAL: &[u8] = b"wasm3_rs_fuel\0";
+pub(crate) const INTERRUPT_GLOBAL: &[u8] = b"wasm3_rs_interrupt\0";
+
+// the amount of fuel moved from the runtime into a module's global at once
+const FUEL_SLICE: u64 = 10_000;
+
+// the sections the instrumentation appends entries to, in the order they have to appear in
+const EXTENDED_SECTIONS: [u8; 4] = [
+    binary::SECTION_TYPE,
+    binary::SECTION_IMPORT,
+    binary::SECTION_GLOBAL,
+    binary::SECTION_EXPORT,
+];
+// modules that aren't metered don't get the hook's type and import
+const EXTENDED_SECTIONS_UNMETERED: [u8; 2] = [binary::SECTION_GLOBAL, binary::SECTION_EXPORT];
+
+/// How a module has been instrumented.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Instrumentation {
+    /// Not at all, the module's bytes are used as they are.
+    None,
+    /// With interrupt checks.
+    Interruptible,
+    /// With interrupt checks and fuel metering.
+    Metered,
+}
+
+/// Instruments the given module binary with interrupt checks and, if `metered` is set, for
+/// fuel metering.
+///
+/// The binary has to have passed [`binary::validate`].
+pub(crate) fn instrument(bytes: &[u8], metered: bool) -> Result<Vec<u8>, ParseError> {
+    let mut instrumenter = Instrumenter {
+        out: Vec::with_capacity(bytes.len() + bytes.len() / 4),
+        metered,
+        hook_type: 0,
+        imported_functions: 0,
+        imported_globals: 0,
+        fuel_global: 0,
+        interrupt_global: 0,
+    };
+    let mut reader = binary::read_header(bytes)?;
+    instrumenter.out.extend_from_slice(binary::MAGIC);
+    instrumenter.out.extend_from_slice(binary::VERSION);
+    let mut missing = if metered {
+        &EXTENDED_SECTIONS[..]
+    } else {
+        &EXTENDED_SECTIONS_UNMETERED[..]
+    };
+    while let Some(section) = binary::read_section(&mut reader)? {
+        let id = section.id;
+        if let Some(order) = binary::section_order(id) {
+            // sections the module lacks are added right before the first section following them
+            while let Some((&next, rest)) = missing.split_first() {
+                if next == id {
+                    missing = rest;
+                } else if binary::section_order(next) < Some(order) {
+                    instrumenter.section(next, Reader::new(&[]))?;
+                    missing = rest;
+                    continue;
+                }
+                break;
+            }
+        }
+        instrumenter
+            .section(id, section.reader)
+            .map_err(|err| err.in_section(id))?;
+    }
+    for &id in missing {
+        instrumenter.section(id, Reader::new(&[]))?;
+    }
+    Ok(instrumenter.out)
+}
+
+struct Instrumenter {
+    out: Vec<u8>,
+    metered: bool,
+    hook_type: u32,
+    imported_functions: u32,
+    imported_globals: u32,
+    fuel_global: u32,
+    interrupt_global: u32,
+}
+
+impl Instrumenter {
+    fn section(&mut self, id: u8, mut reader: Reader<'_>) -> Result<(), ParseError> {
+        let mut content = Vec::with_capacity(reader.remaining().len() + 32);
+        match id {
+            binary::SECTION_CUSTOM => {
+                let name = reader.read_name()?;
+                // names are purely informational, so a malformed name section is dropped
+                // instead of failing the whole module
+                if name == b"name" {
+                    binary::write_name(&mut content, name);
+                    if self.names(&mut reader, &mut content).is_err() {
+                        return Ok(());
+                    }
+                } else {
+                    binary::write_name(&mut content, name);
+                    content.extend_from_slice(reader.remaining());
+                }
+            }
+            binary::SECTION_TYPE if self.metered => {
+                self.hook_type = reader.read_count()?;
+                binary::write_u32(&mut content, self.hook_type + 1);
+                content.extend_from_slice(reader.remaining());
+                content.extend_from_slice(&[0x60, 0x00, 0x00]);
+            }
+            binary::SECTION_IMPORT => {
+                let count = reader.read_count()?;
+                binary::write_u32(&mut content, count + self.metered as u32);
+                content.extend_from_slice(reader.remaining());
+                for _ in 0..count {
+                    match reader.read_import()?.kind {
+                        binary::EXTERNAL_FUNCTION => self.imported_functions += 1,
+                        binary::EXTERNAL_GLOBAL => self.imported_globals += 1,
+                        _ => (),
+                    }
+                }
+                if self.metered {
+                    binary::write_name(&mut content, cstr_bytes(HOOK_MODULE));
+                    binary::write_name(&mut content, cstr_bytes(HOOK_FUNCTION));
+                    content.push(binary::EXTERNAL_FUNCTION);
+                    binary::write_u32(&mut content, self.hook_type);
+                }
+            }
+            binary::SECTION_GLOBAL => {
+                let count = reader.read_count()?;
+                binary::write_u32(&mut content, count + 1 + self.metered as u32);
+                for _ in 0..count {
+                    // value type and mutability
+                    content.extend_from_slice(reader.read_bytes(2)?);
+                    self.const_expr(&mut reader, &mut content)?;
+                }
+                self.fuel_global = self.imported_globals + count;
+                self.interrupt_global = self.fuel_global + self.metered as u32;
+                if self.metered {
+                    // (global (mut i64) (i64.const 0))
+                    content.extend_from_slice(&[0x7e, 0x01, 0x42, 0x00, binary::OP_END]);
+                }
+                // (global (mut i32) (i32.const 0))
+                content.extend_from_slice(&[0x7f, 0x01, 0x41, 0x00, binary::OP_END]);
+            }
+            binary::SECTION_EXPORT => {
+                let count = reader.read_count()?;
+                binary::write_u32(&mut content, count + 1 + self.metered as u32);
+                for _ in 0..count {
+                    binary::write_name(&mut content, reader.read_name()?);
+                    let kind = reader.read_u8()?;
+                    let index = reader.read_u32()?;
+                    content.push(kind);
+                    if kind == binary::EXTERNAL_FUNCTION {
+                        binary::write_u32(&mut content, self.function(index));
+                    } else {
+                        binary::write_u32(&mut content, index);
+                    }
+                }
+                if self.metered {
+                    binary::write_name(&mut content, cstr_bytes(FUEL_GLOBAL));
+                    content.push(binary::EXTERNAL_GLOBAL);
+                    binary::write_u32(&mut content, self.fuel_global);
+                }
+                binary::write_name(&mut content, cstr_bytes(INTERRUPT_GLOBAL));
+                content.push(binary::EXTERNAL_GLOBAL);
+                binary::write_u32(&mut content, self.interrupt_global);
+            }
+            binary::SECTION_START => {
+                let index = reader.read_u32()?;
+                binary::write_u32(&mut content, self.function(index));
+            }
+            binary::SECTION_ELEMENT => self.elements(&mut reader, &mut content)?,
+            binary::SECTION_CODE => {
+                let count = reader.read_u32()?;
+                binary::write_u32(&mut content, count);
+                for index in 0..count {
+                    let function = self.imported_functions + index;
+                    self.body(&mut reader, &mut content)
+                        .map_err(|err| err.in_function(function))?;
+                }
+            }
+            _ => content.extend_from_slice(reader.remaining()),
+        }
+        binary::write_section(&mut self.out, id, &content);
+        Ok(())
+    }
+
+    // the index of a function after the hook has been added as the last imported function
+    fn function(&self, index: u32) -> u32 {
+        if !self.metered || index < self.imported_functions {
+            index
+        } else {
+            index + 1
+        }
+    }
+
+    fn instruction(&self, instruction: &Instruction<'_>, out: &mut Vec<u8>) {
+        match &instruction.function {
+            Some((range, index)) => {
+                out.extend_from_slice(&instruction.bytes[..range.start]);
+                binary::write_u32(out, self.function(*index));
+                out.extend_from_slice(&instruction.bytes[range.end..]);
+            }
+            None => out.extend_from_slice(instruction.bytes),
+        }
+    }
+
+    fn const_expr(&self, reader: &mut Reader<'_>, out: &mut Vec<u8>) -> Result<(), ParseError> {
+        loop {
+            let instruction = reader.read_instruction()?;
+            self.instruction(&instruction, out);
+            if instruction.opcode == binary::OP_END {
+                return Ok(());
+            }
+        }
+    }
+
+    fn elements(&self, reader: &mut Reader<'_>, out: &mut Vec<u8>) -> Result<(), ParseError> {
+        let count = reader.read_u32()?;
+        binary::write_u32(out, count);
+        for _ in 0..count {
+            let flags = reader.read_u32()?;
+            binary::write_u32(out, flags);
+            if flags & 0b010 != 0 && flags & 0b001 == 0 {
+                // explicit table index
+                binary::write_u32(out, reader.read_u32()?);
+            }
+            if flags & 0b001 == 0 {
+                // offset of an active segment
+                self.const_expr(reader, out)?;
+            }
+            if flags & 0b011 != 0 {
+                // element kind or reference type
+                out.push(reader.read_u8()?);
+            }
+            let len = reader.read_u32()?;
+            binary::write_u32(out, len);
+            for _ in 0..len {
+                if flags & 0b100 == 0 {
+                    let index = reader.read_u32()?;
+                    binary::write_u32(out, self.function(index));
+                } else {
+                    self.const_expr(reader, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn body(&self, reader: &mut Reader<'_>, out: &mut Vec<u8>) -> Result<(), ParseError> {
+        let len = reader.read_u32()?;
+        let mut body = reader.sub_reader(len as usize)?;
+        let mut code = Vec::with_capacity(len as usize + 32);
+
+        let locals = body.offset();
+        for _ in 0..body.read_u32()? {
+            body.read_u32()?;
+            body.read_u8()?;
+        }
+        code.extend_from_slice(body.since(locals));
+
+        let mut instructions = Vec::new();
+        // the cost of the function entry and of every loop, in the order they appear in
+        let mut costs = Vec::new();
+        let mut cost = 0;
+        while !body.is_empty() {
+            let instruction = body.read_instruction()?;
+            cost += 1;
+            if instruction.opcode == binary::OP_LOOP {
+                costs.push(cost);
+                cost = 0;
+            }
+            instructions.push(instruction);
+        }
+        costs.push(cost);
+
+        let mut costs = costs.into_iter();
+        self.checkpoint(costs.next().unwrap_or(0), &mut code);
+        for instruction in &instructions {
+            self.instruction(instruction, &mut code);
+            if instruction.opcode == binary::OP_LOOP {
+                self.checkpoint(costs.next().unwrap_or(0), &mut code);
+            }
+        }
+
+        binary::write_u32(out, code.len() as u32);
+        out.extend_from_slice(&code);
+        Ok(())
+    }
+
+    // the code run at the entry of every function and loop
+    fn checkpoint(&self, cost: i64, out: &mut Vec<u8>) {
+        if self.metered {
+            self.charge(cost, out);
+        }
+        // global.get $interrupt, if, unreachable, end
+        out.push(0x23);
+        binary::write_u32(out, self.interrupt_global);
+        out.extend_from_slice(&[binary::OP_IF, 0x40, 0x00, binary::OP_END]);
+    }
+
+    // global.get $fuel, i64.const cost, i64.sub, global.set $fuel,
+    // global.get $fuel, i64.const 0, i64.lt_s, if, call $refuel, end
+    fn charge(&self, cost: i64, out: &mut Vec<u8>) {
+        out.push(0x23);
+        binary::write_u32(out, self.fuel_global);
+        out.push(0x42);
+        binary::write_i64(out, cost);
+        out.extend_from_slice(&[0x7d, 0x24]);
+        binary::write_u32(out, self.fuel_global);
+        out.push(0x23);
+        binary::write_u32(out, self.fuel_global);
+        out.extend_from_slice(&[0x42, 0x00, 0x53, binary::OP_IF, 0x40, 0x10]);
+        binary::write_u32(out, self.imported_functions);
+        out.push(binary::OP_END);
+    }
+
+    fn names(&self, reader: &mut Reader<'_>, out: &mut Vec<u8>) -> Result<(), ParseError> {
+        while !reader.is_empty() {
+            let id = reader.read_u8()?;
+            let len = reader.read_u32()?;
+            let mut subsection = reader.sub_reader(len as usize)?;
+            let mut content = Vec::with_capacity(len as usize);
+            match id {
+                // function names, and local and label names which are indexed by function
+                1..=3 => {
+                    let count = subsection.read_u32()?;
+                    binary::write_u32(&mut content, count);
+                    for _ in 0..count {
+                        let index = subsection.read_u32()?;
+                        binary::write_u32(&mut content, self.function(index));
+                        let start = subsection.offset();
+                        if id == 1 {
+                            subsection.read_name()?;
+                        } else {
+                            for _ in 0..subsection.read_u32()? {
+                                subsection.read_u32()?;
+                                subsection.read_name()?;
+                            }
+                        }
+                        content.extend_from_slice(subsection.since(start));
+                    }
+                }
+                _ => content.extend_from_slice(subsection.remaining()),
+            }
+            out.push(id);
+            binary::write_name(out, &content);
+        }
+        Ok(())
+    }
+}
+
+fn cstr_bytes(cstr: &[u8]) -> &[u8] {
+    &cstr[..cstr.len() - 1]
+}
+
+/// Looks up the fuel global of a loaded metered module.
+pub(crate) unsafe fn find_global(module: ffi::IM3Module) -> ffi::IM3Global {
+    let global = ffi::m3_FindGlobal(module, FUEL_GLOBAL.as_ptr().cast());
+    debug_assert!(!global.is_null(), "metered modules export a fuel global");
+    global
+}
+
+/// Looks up the interrupt global of a loaded instrumented module.
+pub(crate) unsafe fn find_interrupt_global(module: ffi::IM3Module) -> ffi::IM3Global {
+    let global = ffi::m3_FindGlobal(module, INTERRUPT_GLOBAL.as_ptr().cast());
+    debug_assert!(
+        !global.is_null(),
+        "instrumented modules export an interrupt global"
+    );
+    global
+}
+
+/// Links the refuel hook into a loaded metered module, refilling the given fuel global.
+pub(crate) unsafe fn link(module: ffi::IM3Module, global: ffi::IM3Global) -> ffi::M3Result {
+    ffi::m3_LinkRawFunctionEx(
+        module,
+        HOOK_MODULE.as_ptr().cast(),
+        HOOK_FUNCTION.as_ptr().cast(),
+        HOOK_SIGNATURE.as_ptr().cast(),
+        Some(refuel),
+        global.cast(),
+    )
+}
+
+pub(crate) unsafe fn get_fuel(global: ffi::IM3Global) -> i64 {
+    let mut value = ffi::M3TaggedValue {
+        type_: ffi::M3ValueType::c_m3Type_i64,
+        value: ffi::M3TaggedValue_M3ValueUnion { i64_: 0 },
+    };
+    ffi::m3_GetGlobal(global, &mut value);
+    value.value.i64_ as i64
+}
+
+pub(crate) unsafe fn set_fuel(global: ffi::IM3Global, fuel: i64) {
+    let value = ffi::M3TaggedValue {
+        type_: ffi::M3ValueType::c_m3Type_i64,
+        value: ffi::M3TaggedValue_M3ValueUnion { i64_: fuel as u64 },
+    };
+    ffi::m3_SetGlobal(global, &value);
+}
+
+unsafe extern "C" fn refuel(
+    runtime: ffi::IM3Runtime,
+    ctx: ffi::IM3ImportContext,
+    _sp: *mut u64,
+    _mem: *mut cty::c_void,
+) -> *const cty::c_void {
+    let global = (*ctx).userdata.cast::<ffi::M3Global>();
+    let state = RuntimeState::from_raw(runtime);
+    let deficit = get_fuel(global).unsigned_abs();
+    match state.fuel {
+        None => set_fuel(global, FUEL_SLICE as i64),
+        Some(fuel) if fuel >= deficit => {
+            let slice = (fuel - deficit).min(FUEL_SLICE);
+            state.fuel = Some(fuel - deficit - slice);
+            set_fuel(global, slice as i64);
+        }
+        Some(_) => {
+            state.fuel = Some(0);
+            set_fuel(global, 0);
+            return Trap::OutOfFuel.as_ptr().cast();
+        }
+    }
+    ptr::null()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (module (func (export "f") (loop (br 0))))
+    const LOOP: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x03,
+        0x02, 0x01, 0x00, 0x07, 0x05, 0x01, 0x01, 0x66, 0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00,
+        0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b,
+    ];
+
+    #[test]
+    fn test_instrument() {
+        let instrumented = instrument(LOOP, true).unwrap();
+        assert_eq!(binary::validate(&instrumented), Ok(()));
+
+        let mut reader = binary::read_header(&instrumented).unwrap();
+        let mut ids = Vec::new();
+        while let Some(mut section) = binary::read_section(&mut reader).unwrap() {
+            ids.push(section.id);
+            match section.id {
+                binary::SECTION_IMPORT => {
+                    assert_eq!(section.reader.read_u32(), Ok(1));
+                    assert_eq!(section.reader.read_name(), Ok(&b"wasm3_rs"[..]));
+                    assert_eq!(section.reader.read_name(), Ok(&b"refuel"[..]));
+                    // a function of the newly added type
+                    assert_eq!(section.reader.remaining(), [0x00, 0x01]);
+                }
+                binary::SECTION_EXPORT => {
+                    assert_eq!(section.reader.read_u32(), Ok(3));
+                    assert_eq!(section.reader.read_name(), Ok(&b"f"[..]));
+                    // the exported function moved behind the hook
+                    assert_eq!(section.reader.read_bytes(2), Ok(&[0x00, 0x01][..]));
+                }
+                binary::SECTION_CODE => {
+                    let code = section.reader.remaining();
+                    // the function entry is charged for the loop, the loop for its body
+                    let entry = [0x23, 0x00, 0x42, 0x01, 0x7d, 0x24, 0x00];
+                    let body = [0x23, 0x00, 0x42, 0x03, 0x7d, 0x24, 0x00];
+                    assert_eq!(&code[3..10], entry);
+                    assert!(code.windows(body.len()).any(|window| window == body));
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(ids, [1, 2, 3, 6, 7, 10]);
+    }
+
+    #[test]
+    fn test_instrument_interruptible() {
+        let instrumented = instrument(LOOP, false).unwrap();
+        assert_eq!(binary::validate(&instrumented), Ok(()));
+
+        let mut reader = binary::read_header(&instrumented).unwrap();
+        let mut ids = Vec::new();
+        while let Some(mut section) = binary::read_section(&mut reader).unwrap() {
+            ids.push(section.id);
+            match section.id {
+                binary::SECTION_EXPORT => {
+                    assert_eq!(section.reader.read_u32(), Ok(2));
+                    assert_eq!(section.reader.read_name(), Ok(&b"f"[..]));
+                    // without the hook, function indices stay the same
+                    assert_eq!(section.reader.read_bytes(2), Ok(&[0x00, 0x00][..]));
+                    assert_eq!(section.reader.read_name(), Ok(&b"wasm3_rs_interrupt"[..]));
+                }
+                binary::SECTION_CODE => {
+                    let code = section.reader.remaining();
+                    let check = [0x23, 0x00, 0x04, 0x40, 0x00, 0x0b];
+                    assert_eq!(&code[3..9], check);
+                    assert_eq!(
+                        code.windows(check.len())
+                            .filter(|window| *window == check)
+                            .count(),
+                        2
+                    );
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(ids, [1, 3, 6, 7, 10]);
+    }
+}
@@ -1,3 +1,4 @@
+use core::any::Any;
 use core::cmp::{Eq, PartialEq};
 use core::hash::{Hash, Hasher};
 use core::marker::PhantomData;
@@ -5,7 +6,7 @@ use core::ptr::{self, NonNull};
 use core::str;
 
 use crate::error::{Error, Result};
-use crate::runtime::Runtime;
+use crate::runtime::{Runtime, RuntimeState};
 use crate::utils::cstr_to_str;
 use crate::{Module, WasmArg, WasmArgs, WasmType};
 
@@ -23,6 +24,16 @@ impl<'cc> CallContext<'cc> {
         }
     }
 
+    /// Returns a reference to the data attached to the runtime with
+    /// [`RuntimeBuilder::user_data`], if it is of type `T`.
+    ///
+    /// [`RuntimeBuilder::user_data`]: crate::RuntimeBuilder::user_data
+    pub fn user_data<T: Any>(&self) -> Option<&'cc T> {
+        let state = unsafe { ffi::m3_GetUserData(self.runtime.as_ptr()) }.cast::<RuntimeState>();
+        unsafe { (*state).user_data.map(|data| &*data.as_ptr()) }
+            .and_then(|data| data.downcast_ref())
+    }
+
     /// Returns the raw memory of the runtime associated with this context.
     ///
     /// # Safety
@@ -164,8 +175,10 @@ macro_rules! func_call_impl {
             #[inline]
             #[allow(non_snake_case, clippy::too_many_arguments)]
             pub fn call(&self, $($types: $types),*) -> Result<Ret> {
-                let result = unsafe { ffi::m3_CallV(self.raw.as_ptr(), $($types,)*) };
-                Error::from_ffi_res(result)?;
+                let result = self
+                    .rt
+                    .track_call(|| unsafe { ffi::m3_CallV(self.raw.as_ptr(), $($types,)*) });
+                Error::from_call_res(self.rt.as_ptr(), result)?;
                 self.get_call_result()
             }
         }
@@ -182,8 +195,10 @@ where
     /// This is implemented with variable arguments depending on the functions Args type.
     #[inline]
     pub fn call(&self, arg: ARG) -> Result<Ret> {
-        let result = unsafe { ffi::m3_CallV(self.raw.as_ptr(), arg) };
-        Error::from_ffi_res(result)?;
+        let result = self
+            .rt
+            .track_call(|| unsafe { ffi::m3_CallV(self.raw.as_ptr(), arg) });
+        Error::from_call_res(self.rt.as_ptr(), result)?;
         self.get_call_result()
     }
 }
@@ -196,8 +211,10 @@ where
     /// This is implemented with variable arguments depending on the functions Args type.
     #[inline]
     pub fn call(&self) -> Result<Ret> {
-        let result = unsafe { ffi::m3_CallV(self.raw.as_ptr()) };
-        Error::from_ffi_res(result)?;
+        let result = self
+            .rt
+            .track_call(|| unsafe { ffi::m3_CallV(self.raw.as_ptr()) });
+        Error::from_call_res(self.rt.as_ptr(), result)?;
         self.get_call_result()
     }
 }
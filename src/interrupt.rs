@@ -0,0 +1,250 @@
+//! Interrupting calls into runtimes from other threads.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// A handle to interrupt calls into a [`Runtime`], which can be sent to and shared with other
+/// threads.
+///
+/// Interrupts are observed by modules parsed with [`ParsedModule::parse_interruptible`] or
+/// [`ParsedModule::parse_metered`], which are instrumented to check for them at the entry of
+/// every function and loop. Calls into other modules run to completion.
+///
+/// [`Runtime`]: crate::Runtime
+/// [`ParsedModule::parse_interruptible`]: crate::ParsedModule::parse_interruptible
+/// [`ParsedModule::parse_metered`]: crate::ParsedModule::parse_metered
+#[derive(Debug, Clone)]
+pub struct InterruptHandle(pub(crate) Arc<Interrupt>);
+
+impl InterruptHandle {
+    /// Interrupts the call running in the runtime, which then traps with
+    /// [`Trap::Interrupted`] once it enters a function or loop. If no call is running, the next
+    /// call is interrupted instead.
+    ///
+    /// [`Trap::Interrupted`]: crate::error::Trap::Interrupted
+    pub fn interrupt(&self) {
+        self.0.request();
+    }
+}
+
+/// The interrupt state of a runtime, shared with its interrupt handles.
+#[derive(Default)]
+pub(crate) struct Interrupt {
+    // guards `inner`, a spin lock as it is only ever held for a handful of stores and is
+    // needed without `std` as well
+    locked: AtomicBool,
+    inner: UnsafeCell<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // set by interrupt handles until a call observes it
+    requested: bool,
+    // set once the deadline of `Runtime::call_with_timeout` passed, until the call returns
+    timed_out: bool,
+    // the interrupt globals of the runtime's loaded instrumented modules
+    globals: Vec<(ffi::IM3Module, ffi::IM3Global)>,
+}
+
+// SAFETY: `inner` is only accessed with the lock held, and the globals it refers to are only
+// written through atomics while the modules owning them are registered
+unsafe impl Send for Interrupt {}
+unsafe impl Sync for Interrupt {}
+
+impl core::fmt::Debug for Interrupt {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Interrupt").finish_non_exhaustive()
+    }
+}
+
+impl Interrupt {
+    fn with<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *self.inner.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+
+    /// Interrupts the running or next call.
+    pub(crate) fn request(&self) {
+        self.with(|inner| {
+            inner.requested = true;
+            inner.set_globals(1);
+        });
+    }
+
+    /// Interrupts the running call as its timeout passed.
+    #[cfg(feature = "std")]
+    pub(crate) fn time_out(&self) {
+        self.with(|inner| {
+            inner.timed_out = true;
+            inner.set_globals(1);
+        });
+    }
+
+    /// Withdraws an interrupt caused by a timeout that the timed call didn't observe, keeping
+    /// interrupts requested through handles pending.
+    #[cfg(feature = "std")]
+    pub(crate) fn cancel_timeout(&self) {
+        self.with(|inner| {
+            inner.timed_out = false;
+            if !inner.requested {
+                inner.set_globals(0);
+            }
+        });
+    }
+
+    /// Returns whether an interrupt is pending while an interruptible module is loaded, which
+    /// then observes it, clearing it.
+    pub(crate) fn take_pending(&self) -> bool {
+        self.with(|inner| {
+            if inner.globals.is_empty() {
+                return false;
+            }
+            let pending = inner.requested || inner.timed_out;
+            inner.requested = false;
+            inner.timed_out = false;
+            inner.set_globals(0);
+            pending
+        })
+    }
+
+    /// Registers the interrupt global of a loaded module, setting it if an interrupt is
+    /// pending.
+    pub(crate) fn register(&self, module: ffi::IM3Module, global: ffi::IM3Global) {
+        self.with(|inner| {
+            let value = (inner.requested || inner.timed_out) as i32;
+            unsafe { set_global(global, value) };
+            inner.globals.push((module, global));
+        });
+    }
+
+    /// Unregisters the interrupt global of a module before it is freed.
+    pub(crate) fn unregister(&self, module: ffi::IM3Module) {
+        self.with(|inner| inner.globals.retain(|&(raw_mod, _)| raw_mod != module));
+    }
+
+    /// Unregisters all interrupt globals before the runtime owning them is freed.
+    pub(crate) fn unregister_all(&self) {
+        self.with(|inner| inner.globals.clear());
+    }
+
+    /// Returns whether the given global is a registered interrupt global.
+    pub(crate) fn contains(&self, global: ffi::IM3Global) -> bool {
+        self.with(|inner| {
+            inner
+                .globals
+                .iter()
+                .any(|&(_, registered)| registered == global)
+        })
+    }
+}
+
+impl Inner {
+    fn set_globals(&self, value: i32) {
+        for &(_, global) in &self.globals {
+            unsafe { set_global(global, value) };
+        }
+    }
+}
+
+// wasm3 reads the global with plain loads while a call runs on another thread, which is fine
+// for an aligned word that is only ever flipped between 0 and 1
+unsafe fn set_global(global: ffi::IM3Global, value: i32) {
+    let value_ptr = ptr::addr_of_mut!((*global).__bindgen_anon_1).cast::<AtomicI32>();
+    (*value_ptr).store(value, Ordering::Relaxed);
+}
+
+/// The thread timing calls made with [`Runtime::call_with_timeout`], shared by all runtimes.
+///
+/// [`Runtime::call_with_timeout`]: crate::Runtime::call_with_timeout
+#[cfg(feature = "std")]
+pub(crate) mod watchdog {
+    use super::Interrupt;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Condvar, Mutex, MutexGuard, Once, PoisonError};
+    use std::time::{Duration, Instant};
+
+    struct Deadline {
+        at: Instant,
+        id: u64,
+        interrupt: Arc<Interrupt>,
+    }
+
+    static DEADLINES: Mutex<Vec<Deadline>> = Mutex::new(Vec::new());
+    static CHANGED: Condvar = Condvar::new();
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    static STARTED: Once = Once::new();
+
+    fn deadlines() -> MutexGuard<'static, Vec<Deadline>> {
+        DEADLINES.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn run() {
+        let mut deadlines = deadlines();
+        loop {
+            let now = Instant::now();
+            // deadlines fire with the lock held, so that disarming one tells whether it fired
+            deadlines.retain(|deadline| {
+                let passed = deadline.at <= now;
+                if passed {
+                    deadline.interrupt.time_out();
+                }
+                !passed
+            });
+            deadlines = match deadlines.iter().map(|deadline| deadline.at).min() {
+                Some(at) => {
+                    CHANGED
+                        .wait_timeout(deadlines, at - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => CHANGED
+                    .wait(deadlines)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+
+    /// Times out the given interrupt once the timeout passed, returning an id to disarm it
+    /// with, or `None` if the timeout is too long to ever pass.
+    pub(crate) fn arm(timeout: Duration, interrupt: &Arc<Interrupt>) -> Option<u64> {
+        let at = Instant::now().checked_add(timeout)?;
+        STARTED.call_once(|| {
+            std::thread::Builder::new()
+                .name("wasm3-watchdog".into())
+                .spawn(run)
+                .expect("failed to spawn the watchdog thread");
+        });
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        deadlines().push(Deadline {
+            at,
+            id,
+            interrupt: interrupt.clone(),
+        });
+        CHANGED.notify_one();
+        Some(id)
+    }
+
+    /// Disarms the deadline with the given id, returning whether it fired.
+    pub(crate) fn disarm(id: u64) -> bool {
+        let mut deadlines = deadlines();
+        match deadlines.iter().position(|deadline| deadline.id == id) {
+            Some(index) => {
+                deadlines.swap_remove(index);
+                false
+            }
+            None => true,
+        }
+    }
+}
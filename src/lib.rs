@@ -6,17 +6,45 @@ extern crate alloc;
 
 pub mod error;
 
+#[cfg(feature = "std")]
+mod async_runtime;
+#[cfg(feature = "std")]
+pub use self::async_runtime::{block_on, AsyncRuntime, RuntimeFuture};
+mod binary;
+mod builder;
+pub use self::builder::RuntimeBuilder;
+mod determinism;
 mod environment;
 pub use self::environment::Environment;
+mod fuel;
 mod function;
 pub use self::function::{CallContext, Function, RawCall};
+mod interrupt;
+pub use self::interrupt::InterruptHandle;
 mod macros;
 pub use self::macros::*;
 mod module;
-pub use self::module::{Module, ParsedModule};
+pub use self::module::{Module, ModuleId, ParsedModule, SharedModule};
+#[cfg(feature = "std")]
+mod quota;
+#[cfg(feature = "std")]
+pub use self::quota::Quota;
+#[cfg(feature = "std")]
+mod pool;
+#[cfg(feature = "std")]
+pub use self::pool::{PooledRuntime, RuntimePool};
 mod runtime;
 pub use self::runtime::Runtime;
+mod snapshot;
+pub use self::snapshot::Snapshot;
+mod stats;
+pub use self::stats::CallStats;
 mod ty;
-pub use self::ty::{WasmArg, WasmArgs, WasmType};
+pub use self::ty::{Value, WasmArg, WasmArgs, WasmType};
 mod utils;
+pub use self::utils::{MaybeSend, MaybeSync};
+#[cfg(feature = "yield-callback")]
+mod yield_callback;
+#[cfg(feature = "yield-callback")]
+pub use self::yield_callback::YieldAction;
 pub use ffi as wasm3_sys;
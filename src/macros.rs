@@ -1,5 +1,44 @@
 //! Public macros
 
+/// Counts a call to a host function for the runtime's call statistics.
+///
+/// This is an implementation detail of the host function trampolines and [`make_func_wrapper`].
+///
+/// # Safety
+///
+/// `runtime` has to be the runtime the host function has been called by.
+#[doc(hidden)]
+pub unsafe fn count_host_call(runtime: ffi::IM3Runtime) {
+    crate::runtime::RuntimeState::from_raw(runtime).host_calls += 1;
+}
+
+/// Runs the body of a host function, catching any panic so that it doesn't unwind into wasm3.
+/// The payload is stashed in the runtime and returned from the call into wasm as
+/// `Error::HostPanic`. Without the `std` feature panics can't be caught.
+///
+/// This is an implementation detail of the host function trampolines and [`make_func_wrapper`].
+///
+/// # Safety
+///
+/// `runtime` has to be the runtime the host function has been called by.
+#[doc(hidden)]
+pub unsafe fn catch_host_panic(
+    runtime: ffi::IM3Runtime,
+    f: impl FnOnce() -> *const cty::c_void,
+) -> *const cty::c_void {
+    #[cfg(feature = "std")]
+    {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+            crate::error::stash_host_panic(runtime, payload) as *const cty::c_void
+        })
+    }
+    #[cfg(not(feature = "std"))]
+    {
+        let _ = runtime;
+        f()
+    }
+}
+
 /// A convenience macro for creating a wrapper function that can be linked to wasm3.
 ///
 /// # Example
@@ -23,20 +62,23 @@ macro_rules! make_func_wrapper {
             sp: *mut u64,
             _mem: *mut core::ffi::c_void,
         ) -> *const core::ffi::c_void {
-            use $crate::WasmType as _;
-            let mut _argp = sp.add(<$rtype>::SIZE_IN_SLOT_COUNT);
-            $(
-                let $pname = <$ptype as $crate::WasmType>::pop_from_stack(_argp);
-                _argp = _argp.add(<$ptype>::SIZE_IN_SLOT_COUNT);
-            )*
-            let ret = $original( $( $pname ),* );
-            match ret {
-                Ok(ret) => {
-                    <$rtype as $crate::WasmType>::push_on_stack(ret, sp);
-                    $crate::wasm3_sys::m3Err_none as _
-                },
-                Err(trap) => trap.as_ptr() as _
-            }
+            $crate::count_host_call(_rt);
+            $crate::catch_host_panic(_rt, || {
+                use $crate::WasmType as _;
+                let mut _argp = sp.add(<$rtype>::SIZE_IN_SLOT_COUNT);
+                $(
+                    let $pname = <$ptype as $crate::WasmType>::pop_from_stack(_argp);
+                    _argp = _argp.add(<$ptype>::SIZE_IN_SLOT_COUNT);
+                )*
+                let ret = $original( $( $pname ),* );
+                match ret {
+                    Ok(ret) => {
+                        <$rtype as $crate::WasmType>::push_on_stack(ret, sp);
+                        $crate::wasm3_sys::m3Err_none as _
+                    },
+                    Err(trap) => trap.as_ptr() as _
+                }
+            })
         }
     };
     // ptype is an ident because we still want to match on it later -- \/                  rtype too -- \/
@@ -47,20 +89,23 @@ macro_rules! make_func_wrapper {
             sp: *mut u64,
             _mem: *mut core::ffi::c_void,
         ) -> *const core::ffi::c_void {
-            use $crate::WasmType as _;
-            let mut _argp = sp;
-            $(
-                _argp = _argp.add(<$rtype>::SIZE_IN_SLOT_COUNT);
-            )?
-            $(
-                let $pname = <$ptype as $crate::WasmType>::pop_from_stack(_argp);
-                _argp = _argp.add(<$ptype>::SIZE_IN_SLOT_COUNT);
-            )*
-            let _ret = $original( $( $pname ),* );
-            $(
-                <$rtype as $crate::WasmType>::push_on_stack(_ret, sp);
-            )?
-            $crate::wasm3_sys::m3Err_none as _
+            $crate::count_host_call(_rt);
+            $crate::catch_host_panic(_rt, || {
+                use $crate::WasmType as _;
+                let mut _argp = sp;
+                $(
+                    _argp = _argp.add(<$rtype>::SIZE_IN_SLOT_COUNT);
+                )?
+                $(
+                    let $pname = <$ptype as $crate::WasmType>::pop_from_stack(_argp);
+                    _argp = _argp.add(<$ptype>::SIZE_IN_SLOT_COUNT);
+                )*
+                let _ret = $original( $( $pname ),* );
+                $(
+                    <$rtype as $crate::WasmType>::push_on_stack(_ret, sp);
+                )?
+                $crate::wasm3_sys::m3Err_none as _
+            })
         }
     };
 }
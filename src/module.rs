@@ -1,14 +1,27 @@
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
+use core::fmt;
 use core::mem;
+use core::ops::Deref;
 use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::environment::Environment;
-use crate::error::{Error, Result, Trap};
+use crate::binary;
+use crate::determinism;
+use crate::environment::{Environment, Shared};
+#[cfg(feature = "std")]
+use crate::error::QuotaExceeded;
+use crate::error::{Error, HostError, Result, Trap};
+use crate::fuel::{self, Instrumentation};
 use crate::function::{CallContext, Function, RawCall};
+#[cfg(feature = "std")]
+use crate::quota::Quota;
 use crate::runtime::Runtime;
-use crate::utils::{cstr_to_str, str_to_cstr_owned};
+#[cfg(feature = "wasi")]
+use crate::runtime::RuntimeState;
+use crate::utils::{cstr_to_str, str_to_cstr_owned, MaybeSend, MaybeSync};
 
 #[derive(Debug)]
 struct DropModule(NonNull<ffi::M3Module>);
@@ -19,21 +32,178 @@ impl Drop for DropModule {
     }
 }
 
+#[cfg(not(feature = "sync"))]
+type DataSource = dyn AsRef<[u8]>;
+#[cfg(feature = "sync")]
+type DataSource = dyn AsRef<[u8]> + Send + Sync;
+
+/// The bytes backing a module, which wasm3 refers to for as long as the module lives.
+pub(crate) enum ModuleData {
+    Owned(Box<[u8]>),
+    Static(&'static [u8]),
+    Source(Box<DataSource>),
+    Shared(Shared<ModuleData>),
+}
+
+impl Deref for ModuleData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ModuleData::Owned(data) => data,
+            ModuleData::Static(data) => data,
+            ModuleData::Source(source) => (**source).as_ref(),
+            ModuleData::Shared(data) => data,
+        }
+    }
+}
+
+impl fmt::Debug for ModuleData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            ModuleData::Owned(_) => "Owned",
+            ModuleData::Static(_) => "Static",
+            ModuleData::Source(_) => "Source",
+            ModuleData::Shared(_) => "Shared",
+        };
+        f.debug_tuple(kind).field(&self.len()).finish()
+    }
+}
+
 /// A parsed module which can be loaded into a [`Runtime`].
 pub struct ParsedModule {
-    data: Box<[u8]>,
+    data: ModuleData,
     raw: DropModule,
     env: Environment,
+    instrumentation: Instrumentation,
+    // whether the NaNs produced by float arithmetic are canonicalized already
+    deterministic: bool,
 }
 
+// SAFETY: a parsed module is only accessed through its owner until it is loaded, and parsing
+// and loading lock the environment it shares with other modules
+#[cfg(feature = "sync")]
+unsafe impl Send for ParsedModule {}
+
 impl ParsedModule {
     /// Parses a wasm module from raw bytes.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`Error::Parse`] locating the failure if the binary is
+    /// structurally malformed, and wasm3's error if it rejects the module's contents.
     pub fn parse<TData: Into<Box<[u8]>>>(env: &Environment, data: TData) -> Result<Self> {
-        let data = data.into();
+        ParsedModule::parse_data(env, ModuleData::Owned(data.into()))
+    }
+
+    /// Parses a wasm module from static bytes without copying them, for example from bytes
+    /// included with [`include_bytes!`].
+    ///
+    /// # Errors
+    ///
+    /// See [`ParsedModule::parse`].
+    pub fn parse_static(env: &Environment, data: &'static [u8]) -> Result<Self> {
+        ParsedModule::parse_data(env, ModuleData::Static(data))
+    }
+
+    /// Parses a wasm module from reference counted bytes without copying them.
+    ///
+    /// # Errors
+    ///
+    /// See [`ParsedModule::parse`].
+    pub fn parse_shared(env: &Environment, data: Arc<[u8]>) -> Result<Self> {
+        ParsedModule::parse_source(env, data)
+    }
+
+    /// Parses a wasm module from the bytes of the given source without copying them, keeping
+    /// the source alive for as long as the module lives. This allows loading modules from
+    /// memory-mapped files, for example.
+    ///
+    /// The source has to return the same bytes every time it is asked for them, and a mapped
+    /// file must not be modified while the module is alive.
+    ///
+    /// # Errors
+    ///
+    /// See [`ParsedModule::parse`].
+    pub fn parse_source<S>(env: &Environment, source: S) -> Result<Self>
+    where
+        S: AsRef<[u8]> + MaybeSend + MaybeSync + 'static,
+    {
+        ParsedModule::parse_data(env, ModuleData::Source(Box::new(source)))
+    }
+
+    fn parse_data(env: &Environment, data: ModuleData) -> Result<Self> {
+        assert!(data.len() <= !0u32 as usize);
+        binary::validate(&data).map_err(Error::Parse)?;
+        ParsedModule::parse_validated(env, data, Instrumentation::None)
+    }
+
+    /// Parses a wasm module from raw bytes, instrumenting it to check for interrupts at the
+    /// entry of every function and loop, so that calls into it can be interrupted through an
+    /// [`InterruptHandle`].
+    ///
+    /// The checks slow down calls a little. Byte offsets reported in backtraces refer to the
+    /// instrumented binary.
+    ///
+    /// [`InterruptHandle`]: crate::InterruptHandle
+    ///
+    /// # Errors
+    ///
+    /// This function will error like [`ParsedModule::parse`], or with [`Error::Parse`] if the
+    /// module uses instructions that can't be instrumented.
+    pub fn parse_interruptible<TData: Into<Box<[u8]>>>(
+        env: &Environment,
+        data: TData,
+    ) -> Result<Self> {
+        ParsedModule::parse_instrumented(env, data.into(), Instrumentation::Interruptible)
+    }
+
+    /// Parses a wasm module from raw bytes, instrumenting it for fuel metering.
+    ///
+    /// Calls into a metered module consume fuel, roughly one unit per executed instruction,
+    /// and trap with [`Trap::OutOfFuel`] once the fuel set with [`Runtime::set_fuel`] ran out.
+    /// Like [`ParsedModule::parse_interruptible`], this instruments the module to check for
+    /// interrupts as well. As the instrumentation adds an import to the module, function indices reported in
+    /// backtraces of metered modules are off by one for functions defined in the module.
+    ///
+    /// [`Trap::OutOfFuel`]: crate::error::Trap::OutOfFuel
+    ///
+    /// # Errors
+    ///
+    /// This function will error like [`ParsedModule::parse`], or with [`Error::Parse`] if the
+    /// module uses instructions that can't be metered.
+    pub fn parse_metered<TData: Into<Box<[u8]>>>(env: &Environment, data: TData) -> Result<Self> {
+        ParsedModule::parse_instrumented(env, data.into(), Instrumentation::Metered)
+    }
+
+    fn parse_instrumented(
+        env: &Environment,
+        data: Box<[u8]>,
+        instrumentation: Instrumentation,
+    ) -> Result<Self> {
+        binary::validate(&data).map_err(Error::Parse)?;
+        let metered = instrumentation == Instrumentation::Metered;
+        let data = fuel::instrument(&data, metered).map_err(Error::Parse)?;
         assert!(data.len() <= !0u32 as usize);
+        ParsedModule::parse_validated(
+            env,
+            ModuleData::Owned(data.into_boxed_slice()),
+            instrumentation,
+        )
+    }
+
+    fn parse_validated(
+        env: &Environment,
+        data: ModuleData,
+        instrumentation: Instrumentation,
+    ) -> Result<Self> {
         let mut module = ptr::null_mut();
-        let res = unsafe {
-            ffi::m3_ParseModule(env.as_ptr(), &mut module, data.as_ptr(), data.len() as u32)
+        let res = {
+            // parsing adds the module's function types to the environment
+            let _lock = env.lock()?;
+            unsafe {
+                ffi::m3_ParseModule(env.as_ptr(), &mut module, data.as_ptr(), data.len() as u32)
+            }
         };
         Error::from_ffi_res(res)?;
         let module = NonNull::new(module)
@@ -42,29 +212,218 @@ impl ParsedModule {
             data,
             raw: DropModule(module),
             env: env.clone(),
+            instrumentation,
+            deterministic: false,
         })
     }
 
+    /// Parses the module anew with the NaNs produced by float arithmetic canonicalized, unless
+    /// they are already. This copies the module's bytes.
+    pub(crate) fn into_deterministic(self) -> Result<Self> {
+        if self.deterministic {
+            return Ok(self);
+        }
+        let data = determinism::instrument(&self.data).map_err(Error::Parse)?;
+        assert!(data.len() <= !0u32 as usize);
+        let mut module = ParsedModule::parse_validated(
+            &self.env,
+            ModuleData::Owned(data.into_boxed_slice()),
+            self.instrumentation,
+        )?;
+        module.deterministic = true;
+        Ok(module)
+    }
+
     pub(crate) fn as_ptr(&self) -> ffi::IM3Module {
         self.raw.0.as_ptr()
     }
 
-    pub(crate) fn take_data(self) -> Box<[u8]> {
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub(crate) fn take_data(self) -> ModuleData {
         let ParsedModule {
             data,
             raw,
             env: _env,
+            instrumentation: _,
+            deterministic: _,
         } = self;
         mem::forget(raw);
         data
     }
 
+    /// Whether this module has been instrumented for fuel metering.
+    pub fn is_metered(&self) -> bool {
+        self.instrumentation == Instrumentation::Metered
+    }
+
+    /// Whether calls into this module can be interrupted, see
+    /// [`ParsedModule::parse_interruptible`].
+    pub fn is_interruptible(&self) -> bool {
+        self.instrumentation != Instrumentation::None
+    }
+
+    /// The environment this module was parsed in.
+    pub fn environment(&self) -> &Environment {
+        &self.env
+    }
+}
+
+/// A parsed module whose bytes are shared by all runtimes it is loaded into.
+///
+/// Loading a [`ParsedModule`] moves its bytes into the runtime, so each runtime needs a module
+/// parsed for it. A shared module is validated, and instrumented if requested, only once and
+/// can be loaded into any number of runtimes of its environment, each of which gets an
+/// instance of its own that refers to the same bytes. Cloning a shared module is cheap.
+///
+/// wasm3 still parses every instance anew, as a module it parsed is bound to the runtime it
+/// is loaded into and can't be cloned. Sharing saves the copy of the bytes, validation and
+/// instrumentation, but not wasm3's parsing of the sections, which only decodes their
+/// entries. Functions are compiled per runtime either way.
+///
+/// ```ignore
+/// let module = SharedModule::parse(&env, bytes)?;
+/// for runtime in &runtimes {
+///     runtime.load_shared_module(&module)?;
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SharedModule {
+    data: Shared<ModuleData>,
+    env: Environment,
+    instrumentation: Instrumentation,
+    deterministic: bool,
+}
+
+impl SharedModule {
+    /// Parses a wasm module from raw bytes, see [`ParsedModule::parse`].
+    ///
+    /// # Errors
+    ///
+    /// See [`ParsedModule::parse`].
+    pub fn parse<TData: Into<Box<[u8]>>>(env: &Environment, data: TData) -> Result<Self> {
+        ParsedModule::parse(env, data).map(SharedModule::from)
+    }
+
+    /// Parses a wasm module from raw bytes, instrumenting it to check for interrupts, see
+    /// [`ParsedModule::parse_interruptible`].
+    ///
+    /// # Errors
+    ///
+    /// See [`ParsedModule::parse_interruptible`].
+    pub fn parse_interruptible<TData: Into<Box<[u8]>>>(
+        env: &Environment,
+        data: TData,
+    ) -> Result<Self> {
+        ParsedModule::parse_interruptible(env, data).map(SharedModule::from)
+    }
+
+    /// Parses a wasm module from raw bytes, instrumenting it for fuel metering, see
+    /// [`ParsedModule::parse_metered`].
+    ///
+    /// # Errors
+    ///
+    /// See [`ParsedModule::parse_metered`].
+    pub fn parse_metered<TData: Into<Box<[u8]>>>(env: &Environment, data: TData) -> Result<Self> {
+        ParsedModule::parse_metered(env, data).map(SharedModule::from)
+    }
+
+    /// Creates a new instance of this module, ready to be loaded into a runtime, which wasm3
+    /// parses from the shared bytes.
+    ///
+    /// # Errors
+    ///
+    /// This function will error on memory allocation failure.
+    pub fn instantiate(&self) -> Result<ParsedModule> {
+        let mut module = ParsedModule::parse_validated(
+            &self.env,
+            ModuleData::Shared(self.data.clone()),
+            self.instrumentation,
+        )?;
+        module.deterministic = self.deterministic;
+        Ok(module)
+    }
+
+    /// Instruments this module for [deterministic] runtimes once, so that its instances share
+    /// the instrumented bytes.
+    ///
+    /// Deterministic runtimes instrument the modules loaded into them, which copies the bytes
+    /// of every instance of a shared module that hasn't been instrumented this way. Instances
+    /// of an instrumented module behave the same in runtimes that aren't deterministic, apart
+    /// from the NaNs produced by float arithmetic being canonical.
+    ///
+    /// [deterministic]: crate::RuntimeBuilder::deterministic
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`Error::Parse`] if the module can't be executed
+    /// deterministically.
+    pub fn into_deterministic(self) -> Result<Self> {
+        if self.deterministic {
+            return Ok(self);
+        }
+        self.instantiate()?
+            .into_deterministic()
+            .map(SharedModule::from)
+    }
+
+    /// Whether this module has been instrumented for fuel metering.
+    pub fn is_metered(&self) -> bool {
+        self.instrumentation == Instrumentation::Metered
+    }
+
+    /// Whether calls into this module can be interrupted, see
+    /// [`ParsedModule::parse_interruptible`].
+    pub fn is_interruptible(&self) -> bool {
+        self.instrumentation != Instrumentation::None
+    }
+
     /// The environment this module was parsed in.
     pub fn environment(&self) -> &Environment {
         &self.env
     }
 }
 
+impl From<ParsedModule> for SharedModule {
+    fn from(module: ParsedModule) -> Self {
+        let ParsedModule {
+            data,
+            raw,
+            env,
+            instrumentation,
+            deterministic,
+        } = module;
+        // only the bytes are kept, instances are parsed from them anew
+        drop(raw);
+        let data = match data {
+            ModuleData::Shared(data) => data,
+            data => Shared::new(data),
+        };
+        SharedModule {
+            data,
+            env,
+            instrumentation,
+            deterministic,
+        }
+    }
+}
+
+/// Identifies a module loaded into a runtime without borrowing the runtime like a [`Module`]
+/// does, see [`Runtime::unload_module`].
+///
+/// Identifiers are unique among all modules loaded into any runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ModuleId(usize);
+
+impl ModuleId {
+    pub(crate) fn next() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        ModuleId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 /// A loaded module belonging to a specific runtime. Allows for linking and looking up functions.
 // needs no drop as loaded modules will be cleaned up by the runtime
 pub struct Module<'rt> {
@@ -112,6 +471,7 @@ impl<'rt> Module<'rt> {
         let function_name_cstr = str_to_cstr_owned(function_name);
         let signature = function_signature::<Args, Ret>();
 
+        let _lock = self.rt.lock_environment()?;
         let result = unsafe {
             ffi::m3_LinkRawFunction(
                 self.raw,
@@ -121,11 +481,12 @@ impl<'rt> Module<'rt> {
                 Some(f),
             )
         };
-        Error::from_ffi_res(result)
+        Error::from_runtime_res(self.rt.as_ptr(), result)
     }
 
     /// Links the given closure to the corresponding module and function name.
-    /// This boxes the closure and therefor requires a heap allocation.
+    /// This boxes the closure and therefor requires a heap allocation. With the `sync`
+    /// feature enabled, the closure has to be `Send`.
     ///
     /// # Errors
     ///
@@ -135,6 +496,38 @@ impl<'rt> Module<'rt> {
     /// * no function by the given name in the given module could be found
     /// * the function has been found but the signature did not match
     pub fn link_closure<Args, Ret, F>(
+        &mut self,
+        module_name: &str,
+        function_name: &str,
+        mut closure: F,
+    ) -> Result<()>
+    where
+        Args: crate::WasmArgs,
+        Ret: crate::WasmType,
+        F: for<'cc> FnMut(CallContext<'cc>, Args) -> core::result::Result<Ret, Trap>
+            + MaybeSend
+            + 'static,
+    {
+        self.link_fallible_closure(
+            module_name,
+            function_name,
+            move |ctx: CallContext<'_>, args: Args| closure(ctx, args).map_err(HostError::from),
+        )
+    }
+
+    /// Links the given closure like [`Module::link_closure`], letting it fail with a
+    /// [`HostError`] rather than just a [`Trap`].
+    ///
+    /// With the `std` feature enabled, a host error may wrap a custom error, which is then
+    /// returned from the call into wasm as [`Error::Host`].
+    ///
+    /// [`Trap`]: crate::error::Trap
+    /// [`Error::Host`]: crate::error::Error::Host
+    ///
+    /// # Errors
+    ///
+    /// This function will error like [`Module::link_closure`].
+    pub fn link_fallible_closure<Args, Ret, F>(
         &mut self,
         module_name: &str,
         function_name: &str,
@@ -143,7 +536,9 @@ impl<'rt> Module<'rt> {
     where
         Args: crate::WasmArgs,
         Ret: crate::WasmType,
-        F: for<'cc> FnMut(CallContext<'cc>, Args) -> core::result::Result<Ret, Trap> + 'static,
+        F: for<'cc> FnMut(CallContext<'cc>, Args) -> core::result::Result<Ret, HostError>
+            + MaybeSend
+            + 'static,
     {
         unsafe extern "C" fn trampoline<Args, Ret, F>(
             runtime: ffi::IM3Runtime,
@@ -154,7 +549,9 @@ impl<'rt> Module<'rt> {
         where
             Args: crate::WasmArgs,
             Ret: crate::WasmType,
-            F: for<'cc> FnMut(CallContext<'cc>, Args) -> core::result::Result<Ret, Trap> + 'static,
+            F: for<'cc> FnMut(CallContext<'cc>, Args) -> core::result::Result<Ret, HostError>
+                + MaybeSend
+                + 'static,
         {
             let runtime = NonNull::new(runtime)
                 .expect("wasm3 calls imported functions with non-null runtime");
@@ -163,16 +560,19 @@ impl<'rt> Module<'rt> {
             let mut closure = NonNull::new(ctx.as_ref().userdata as *mut F)
                 .expect("userdata passed to m3_LinkRawFunctionEx is non-null");
 
-            let args = Args::pop_from_stack(sp.add(Ret::SIZE_IN_SLOT_COUNT));
-            let ret = closure.as_mut()(CallContext::from_rt(runtime), args);
-            let result = match ret {
-                Ok(ret) => {
-                    ret.push_on_stack(sp);
-                    ffi::m3Err_none
-                }
-                Err(trap) => trap.as_ptr(),
-            };
-            result as *const cty::c_void
+            crate::count_host_call(runtime.as_ptr());
+            crate::catch_host_panic(runtime.as_ptr(), || {
+                let args = Args::pop_from_stack(sp.add(Ret::SIZE_IN_SLOT_COUNT));
+                let ret = closure.as_mut()(CallContext::from_rt(runtime), args);
+                let result = match ret {
+                    Ok(ret) => {
+                        ret.push_on_stack(sp);
+                        ffi::m3Err_none
+                    }
+                    Err(err) => err.into_ffi_res(runtime.as_ptr()),
+                };
+                result as *const cty::c_void
+            })
         }
 
         let module_name_cstr = str_to_cstr_owned(module_name);
@@ -180,21 +580,67 @@ impl<'rt> Module<'rt> {
         let signature = function_signature::<Args, Ret>();
 
         let mut closure = Box::pin(closure);
-        let result = unsafe {
-            ffi::m3_LinkRawFunctionEx(
-                self.raw,
-                module_name_cstr.as_ptr(),
-                function_name_cstr.as_ptr(),
-                signature.as_ptr(),
-                Some(trampoline::<Args, Ret, F>),
-                closure.as_mut().get_unchecked_mut() as *mut F as *const cty::c_void,
-            )
+        let result = {
+            let _lock = self.rt.lock_environment()?;
+            unsafe {
+                ffi::m3_LinkRawFunctionEx(
+                    self.raw,
+                    module_name_cstr.as_ptr(),
+                    function_name_cstr.as_ptr(),
+                    signature.as_ptr(),
+                    Some(trampoline::<Args, Ret, F>),
+                    closure.as_mut().get_unchecked_mut() as *mut F as *const cty::c_void,
+                )
+            }
         };
-        Error::from_ffi_res(result)?;
-        self.rt.push_closure(closure);
+        Error::from_runtime_res(self.rt.as_ptr(), result)?;
+        self.rt.push_closure(self.raw, closure);
         Ok(())
     }
 
+    /// Links the given closure like [`Module::link_fallible_closure`], charging `cost` against
+    /// `quota` for each call.
+    ///
+    /// Calls that would cost more than what remains of the quota don't reach the closure, and
+    /// fail with a [`QuotaExceeded`] error naming the import instead, which is returned from the
+    /// call into wasm as [`Error::Host`].
+    ///
+    /// [`QuotaExceeded`]: crate::error::QuotaExceeded
+    /// [`Error::Host`]: crate::error::Error::Host
+    ///
+    /// # Errors
+    ///
+    /// This function will error like [`Module::link_closure`].
+    #[cfg(feature = "std")]
+    pub fn link_closure_with_quota<Args, Ret, F>(
+        &mut self,
+        module_name: &str,
+        function_name: &str,
+        quota: &Quota,
+        cost: u64,
+        mut closure: F,
+    ) -> Result<()>
+    where
+        Args: crate::WasmArgs,
+        Ret: crate::WasmType,
+        F: for<'cc> FnMut(CallContext<'cc>, Args) -> core::result::Result<Ret, HostError>
+            + MaybeSend
+            + 'static,
+    {
+        let quota = quota.clone();
+        let (module, function) = (module_name.to_owned(), function_name.to_owned());
+        self.link_fallible_closure(
+            module_name,
+            function_name,
+            move |ctx: CallContext<'_>, args: Args| {
+                quota.charge(cost).map_err(|remaining| {
+                    HostError::new(QuotaExceeded::new(&module, &function, cost, remaining))
+                })?;
+                closure(ctx, args)
+            },
+        )
+    }
+
     /// Looks up a function by the given name in this module.
     ///
     /// # Errors
@@ -204,6 +650,11 @@ impl<'rt> Module<'rt> {
     /// * a memory allocation failed
     /// * no function by the given name in the given module could be found
     /// * the function has been found but the signature did not match
+    /// * the runtime compiles eagerly and a function of the module failed to compile, for
+    ///   example as it calls an import that hasn't been linked yet, see
+    ///   [`RuntimeBuilder::compile_eagerly`]
+    ///
+    /// [`RuntimeBuilder::compile_eagerly`]: crate::RuntimeBuilder::compile_eagerly
     pub fn find_function<Args, Ret>(&self, function_name: &str) -> Result<Function<'rt, Args, Ret>>
     where
         Args: crate::WasmArgs,
@@ -216,15 +667,42 @@ impl<'rt> Module<'rt> {
         }
     }
 
+    /// The identifier of this module, which can be used to unload it.
+    pub fn id(&self) -> ModuleId {
+        self.rt.module_id(self.raw)
+    }
+
     /// The name of this module.
     pub fn name(&self) -> &str {
         unsafe { cstr_to_str(ffi::m3_GetModuleName(self.raw)) }
     }
 
     /// Links wasi to this module.
+    ///
+    /// Calls into wasm that end in the program calling `proc_exit` fail with [`Error::Exit`]
+    /// carrying the program's exit code. In a [deterministic] runtime, the clocks report a time
+    /// of zero and `random_get` fills its buffer with zeros.
+    ///
+    /// [deterministic]: crate::RuntimeBuilder::deterministic
     #[cfg(feature = "wasi")]
     pub fn link_wasi(&mut self) -> Result<()> {
-        unsafe { Error::from_ffi_res(ffi::m3_LinkWASI(self.raw)) }
+        let result = {
+            let _lock = self.rt.lock_environment()?;
+            unsafe { ffi::m3_LinkWASI(self.raw) }
+        };
+        Error::from_runtime_res(self.rt.as_ptr(), result)?;
+        // wasm3's `proc_exit` records the exit code in a context shared by all runtimes, so it
+        // is replaced by one recording it in this runtime's state
+        for &wasi in &determinism::WASI_MODULES {
+            match self.link_function::<i32, ()>(wasi, "proc_exit", proc_exit) {
+                Ok(()) | Err(Error::FunctionNotFound) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        if self.rt.is_deterministic() {
+            determinism::link_wasi_stubs(self)?;
+        }
+        Ok(())
     }
 }
 
@@ -234,6 +712,18 @@ impl<'rt> Module<'rt> {
     }
 }
 
+#[cfg(feature = "wasi")]
+unsafe extern "C" fn proc_exit(
+    runtime: ffi::IM3Runtime,
+    _ctx: ffi::IM3ImportContext,
+    sp: *mut u64,
+    _mem: *mut cty::c_void,
+) -> *const cty::c_void {
+    let code = <i32 as crate::WasmType>::pop_from_stack(sp);
+    RuntimeState::from_raw(runtime).exit_code = Some(code);
+    ffi::m3Err_trapExit.cast()
+}
+
 fn function_signature<Args, Ret>() -> Vec<cty::c_char>
 where
     Args: crate::WasmArgs,
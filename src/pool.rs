@@ -0,0 +1,157 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::ManuallyDrop;
+use core::ops::Deref;
+use std::sync::{Mutex, PoisonError};
+
+use crate::error::Result;
+use crate::module::{Module, SharedModule};
+use crate::runtime::Runtime;
+use crate::utils::{MaybeSend, MaybeSync};
+
+#[cfg(not(feature = "sync"))]
+type CreateFn = dyn Fn() -> Result<Runtime>;
+#[cfg(feature = "sync")]
+type CreateFn = dyn Fn() -> Result<Runtime> + Send + Sync;
+#[cfg(not(feature = "sync"))]
+type LinkFn = dyn for<'rt> Fn(&mut Module<'rt>) -> Result<()>;
+#[cfg(feature = "sync")]
+type LinkFn = dyn for<'rt> Fn(&mut Module<'rt>) -> Result<()> + Send + Sync;
+
+/// A pool of runtimes which have a module loaded and linked, ready to serve calls.
+///
+/// The module is parsed once as a [`SharedModule`], which every runtime loads an instance of.
+///
+/// Runtimes are handed out as [`PooledRuntime`]s and return to the pool once these are dropped.
+/// A runtime keeps the state its module's memory and globals were left in by earlier calls, but
+/// runtimes in which a call failed, be it due to a trap or a failing host function, are
+/// discarded instead of being returned. When the pool runs dry, new runtimes are created on
+/// demand, and no more than the pool's size are kept around.
+///
+/// With the `sync` feature enabled, the pool can be shared between threads.
+///
+/// ```ignore
+/// let pool = RuntimePool::new(
+///     SharedModule::parse(&env, bytes)?,
+///     8,
+///     move || env.create_runtime(64 * 1024),
+///     |module| module.link_closure("env", "log", |_, ptr: u32| Ok(())),
+/// )?;
+/// let runtime = pool.get()?;
+/// let handle = runtime.find_function::<u32, u32>("handle")?;
+/// handle.call(42)?;
+/// ```
+pub struct RuntimePool {
+    module: SharedModule,
+    size: usize,
+    create: Box<CreateFn>,
+    link: Box<LinkFn>,
+    idle: Mutex<Vec<Runtime>>,
+}
+
+impl RuntimePool {
+    /// Creates a pool of `size` runtimes created by `create`, each having an instance of
+    /// `module` loaded and linked by `link`.
+    ///
+    /// # Errors
+    ///
+    /// This function will error if creating a runtime, loading the module or linking it fails.
+    pub fn new<C, L>(module: SharedModule, size: usize, create: C, link: L) -> Result<Self>
+    where
+        C: Fn() -> Result<Runtime> + MaybeSend + MaybeSync + 'static,
+        L: for<'rt> Fn(&mut Module<'rt>) -> Result<()> + MaybeSend + MaybeSync + 'static,
+    {
+        let pool = RuntimePool {
+            module,
+            size,
+            create: Box::new(create),
+            link: Box::new(link),
+            idle: Mutex::new(Vec::with_capacity(size)),
+        };
+        let runtimes = (0..size)
+            .map(|_| pool.create())
+            .collect::<Result<Vec<_>>>()?;
+        *pool.idle() = runtimes;
+        Ok(pool)
+    }
+
+    /// Takes a runtime from the pool, creating a new one if none is idle.
+    ///
+    /// # Errors
+    ///
+    /// This function will error if a new runtime had to be created and that failed.
+    pub fn get(&self) -> Result<PooledRuntime<'_>> {
+        let idle = self.idle().pop();
+        let runtime = match idle {
+            Some(runtime) => runtime,
+            None => self.create()?,
+        };
+        Ok(PooledRuntime {
+            pool: self,
+            runtime: ManuallyDrop::new(runtime),
+        })
+    }
+
+    /// The number of runtimes ready to be handed out without creating a new one.
+    pub fn idle_count(&self) -> usize {
+        self.idle().len()
+    }
+
+    /// The number of idle runtimes the pool keeps at most.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn create(&self) -> Result<Runtime> {
+        let runtime = (self.create)()?;
+        (self.link)(&mut runtime.load_shared_module(&self.module)?)?;
+        Ok(runtime)
+    }
+
+    fn recycle(&self, runtime: Runtime) {
+        if runtime.take_call_failed() {
+            return;
+        }
+        runtime.reset_error_state();
+        let mut idle = self.idle();
+        if idle.len() < self.size {
+            idle.push(runtime);
+        }
+    }
+
+    fn idle(&self) -> std::sync::MutexGuard<'_, Vec<Runtime>> {
+        // runtimes are only pushed and popped while locked, which can't panic halfway
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A runtime taken from a [`RuntimePool`], which returns to the pool when dropped.
+pub struct PooledRuntime<'pool> {
+    pool: &'pool RuntimePool,
+    runtime: ManuallyDrop<Runtime>,
+}
+
+impl PooledRuntime<'_> {
+    /// Drops the runtime instead of returning it to the pool.
+    pub fn discard(mut self) {
+        // SAFETY: the runtime is not accessed again, as `self` is forgotten right after
+        unsafe { ManuallyDrop::drop(&mut self.runtime) };
+        core::mem::forget(self);
+    }
+}
+
+impl Deref for PooledRuntime<'_> {
+    type Target = Runtime;
+
+    fn deref(&self) -> &Runtime {
+        &self.runtime
+    }
+}
+
+impl Drop for PooledRuntime<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the runtime is taken exactly once, as this runs only once
+        let runtime = unsafe { ManuallyDrop::take(&mut self.runtime) };
+        self.pool.recycle(runtime);
+    }
+}
@@ -0,0 +1,75 @@
+use alloc::sync::Arc;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// A budget spent by calls to host functions linked with [`Module::link_closure_with_quota`].
+///
+/// Each such function charges its cost against the quota per call, and fails with
+/// [`QuotaExceeded`] once a call would cost more than what remains. A quota is cheap to clone,
+/// with all clones sharing the same budget, so it can be passed to several imports that draw
+/// from a common budget.
+///
+/// [`Module::link_closure_with_quota`]: crate::Module::link_closure_with_quota
+/// [`QuotaExceeded`]: crate::error::QuotaExceeded
+#[derive(Clone, Debug)]
+pub struct Quota(Arc<QuotaState>);
+
+#[derive(Debug)]
+struct QuotaState {
+    budget: u64,
+    // the period after which the budget is restored
+    period: Option<Duration>,
+    // the remaining budget and the start of the current period
+    spent: Mutex<(u64, Instant)>,
+}
+
+impl Quota {
+    /// Creates a quota of the given budget, which stays spent until it is [`reset`].
+    ///
+    /// [`reset`]: Quota::reset
+    pub fn new(budget: u64) -> Self {
+        Quota::with_period(budget, None)
+    }
+
+    /// Creates a rate limit, a quota of the given budget which is restored every `period`.
+    pub fn per_period(budget: u64, period: Duration) -> Self {
+        Quota::with_period(budget, Some(period))
+    }
+
+    fn with_period(budget: u64, period: Option<Duration>) -> Self {
+        Quota(Arc::new(QuotaState {
+            budget,
+            period,
+            spent: Mutex::new((budget, Instant::now())),
+        }))
+    }
+
+    /// The budget that remains to be spent.
+    pub fn remaining(&self) -> u64 {
+        self.lock().0
+    }
+
+    /// Restores the full budget.
+    pub fn reset(&self) {
+        *self.lock() = (self.0.budget, Instant::now());
+    }
+
+    /// Spends the given cost, or returns the remaining budget if it doesn't cover the cost.
+    pub(crate) fn charge(&self, cost: u64) -> Result<(), u64> {
+        let mut spent = self.lock();
+        if cost > spent.0 {
+            return Err(spent.0);
+        }
+        spent.0 -= cost;
+        Ok(())
+    }
+
+    // locks the remaining budget, restoring it first if the period is over
+    fn lock(&self) -> MutexGuard<'_, (u64, Instant)> {
+        let mut spent = self.0.spent.lock().unwrap_or_else(PoisonError::into_inner);
+        if matches!(self.0.period, Some(period) if spent.1.elapsed() >= period) {
+            *spent = (self.0.budget, Instant::now());
+        }
+        spent
+    }
+}
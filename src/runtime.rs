@@ -1,48 +1,183 @@
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::cell::UnsafeCell;
+use core::any::Any;
+use core::cell::{Cell, UnsafeCell};
+use core::hash::Hasher;
 use core::pin::Pin;
 use core::ptr::{self, NonNull};
+#[cfg(feature = "std")]
+use std::time::Duration;
 
-use crate::environment::Environment;
-use crate::error::{Error, Result};
-use crate::function::Function;
-use crate::module::{Module, ParsedModule};
-use crate::utils::str_to_cstr_owned;
+use crate::binary;
+use crate::builder::{self, RuntimeBuilder, RuntimeConfig};
+use crate::environment::{Environment, EnvironmentLock};
+use crate::error::{Error, Result, Trap};
+use crate::fuel;
+use crate::function::{Function, NNM3Function};
+#[cfg(feature = "std")]
+use crate::interrupt::watchdog;
+use crate::interrupt::{Interrupt, InterruptHandle};
+use crate::module::{Module, ModuleData, ModuleId, ParsedModule, SharedModule};
+use crate::snapshot::Snapshot;
+use crate::stats::{CallStats, CallTracker};
+use crate::ty::Value;
+#[cfg(feature = "yield-callback")]
+use crate::utils::MaybeSend;
+use crate::utils::{str_to_cstr_owned, Fnv1a};
+#[cfg(feature = "yield-callback")]
+use crate::yield_callback::{self, YieldAction, YieldCallback};
 
-type PinnedAnyClosure = Pin<Box<dyn core::any::Any + 'static>>;
+/// Data owned by a runtime, which has to be `Send` for the runtime to be with the `sync`
+/// feature enabled.
+#[cfg(not(feature = "sync"))]
+pub(crate) type AnyData = dyn Any;
+/// Data owned by a runtime, which has to be `Send` for the runtime to be with the `sync`
+/// feature enabled.
+#[cfg(feature = "sync")]
+pub(crate) type AnyData = dyn Any + Send;
+
+type PinnedAnyClosure = Pin<Box<AnyData>>;
+
+/// State shared between a [`Runtime`] and the host functions linked into it.
+/// It is handed to wasm3 as the runtime's userdata so that trampolines can reach it.
+#[derive(Default)]
+pub(crate) struct RuntimeState {
+    // error of a failed host function, picked up once the call into wasm returns
+    #[cfg(feature = "std")]
+    pub(crate) host_error: Option<Box<dyn std::error::Error + Send + Sync>>,
+    // payload of a panicking host function, picked up once the call into wasm returns
+    #[cfg(feature = "std")]
+    pub(crate) host_panic: Option<Box<dyn core::any::Any + Send>>,
+    // fuel that has not been handed out to metered modules yet, or `None` if fuel is unlimited
+    pub(crate) fuel: Option<u64>,
+    // shared with interrupt handles, cleared once a call has been interrupted
+    pub(crate) interrupt: Arc<Interrupt>,
+    // only ever shared, so host functions can hand out references to it while the state is
+    // mutated, freed on drop
+    pub(crate) user_data: Option<NonNull<AnyData>>,
+    // exit code of a wasm program that called wasi's `proc_exit`, picked up once the call into
+    // wasm returns
+    #[cfg(feature = "wasi")]
+    pub(crate) exit_code: Option<i32>,
+    // the number of calls made to host functions over the runtime's lifetime
+    pub(crate) host_calls: u64,
+    // run whenever wasm3 yields during a call
+    #[cfg(feature = "yield-callback")]
+    pub(crate) yield_callback: Option<Box<YieldCallback>>,
+}
+
+impl Drop for RuntimeState {
+    fn drop(&mut self) {
+        if let Some(user_data) = self.user_data {
+            drop(unsafe { Box::from_raw(user_data.as_ptr()) });
+        }
+    }
+}
+
+impl RuntimeState {
+    /// # Safety
+    ///
+    /// The runtime has to be created by [`Runtime::new`] and the returned reference must not
+    /// outlive it or alias another reference to its state.
+    pub(crate) unsafe fn from_raw<'a>(runtime: ffi::IM3Runtime) -> &'a mut RuntimeState {
+        &mut *ffi::m3_GetUserData(runtime).cast::<RuntimeState>()
+    }
+}
 
 /// A runtime context for wasm3 modules.
+///
+/// With the `sync` feature enabled, runtimes are `Send` and can be moved to other threads
+/// together with the closures linked into them, which are required to be `Send` as well.
+///
+/// A runtime stays usable after a call into it has failed, be it due to a trap, a stack
+/// overflow or a failing host function. The wasm stack is unwound on every failure and the
+/// state of linear memory and globals is left as the guest had written it up to the failure,
+/// so further calls behave as if the failing call had returned early. The details of the last
+/// failure are kept until the next one occurs or until [`Runtime::reset_error_state`] is called.
 #[derive(Debug)]
 pub struct Runtime {
     raw: NonNull<ffi::M3Runtime>,
+    // owned state handed to wasm3 as userdata, freed on drop
+    state: NonNull<RuntimeState>,
     environment: Environment,
-    // holds all linked closures so that they properly get disposed of when runtime drops
-    closure_store: UnsafeCell<Vec<PinnedAnyClosure>>,
+    // holds all linked closures so that they properly get disposed of when runtime drops,
+    // tagged with the module they were linked into so that they can be released on unload
+    closure_store: UnsafeCell<Vec<(ffi::IM3Module, PinnedAnyClosure)>>,
     // holds all backing data of loaded modules as they have to be kept alive for the module's lifetime
-    module_data: UnsafeCell<Vec<Box<[u8]>>>,
+    module_data: UnsafeCell<Vec<(ffi::IM3Module, ModuleData)>>,
+    // the identifiers of loaded modules, which unlike their pointers are never reused
+    module_ids: UnsafeCell<Vec<(ffi::IM3Module, ModuleId)>>,
+    // the fuel globals of loaded metered modules
+    fuel_globals: UnsafeCell<Vec<(ffi::IM3Module, ffi::IM3Global)>>,
+    // hashes of the backing data of loaded modules, computed once a snapshot needs them
+    module_hashes: UnsafeCell<Vec<(ffi::IM3Module, u64)>>,
+    // loaded modules to be compiled as a whole once one of their functions is looked up
+    uncompiled: UnsafeCell<Vec<ffi::IM3Module>>,
+    config: RuntimeConfig,
+    last_call_stats: Cell<Option<CallStats>>,
+    // the number of calls in progress, more than one if host functions call back into wasm
+    call_depth: Cell<u32>,
+    // whether a call into the runtime failed since this was last cleared
+    call_failed: Cell<bool>,
 }
 
 impl Runtime {
-    /// Creates a new runtime with the given stack size in slots.
+    /// Creates a new runtime with the given stack size in bytes.
+    ///
+    /// See [`RuntimeBuilder`] for further configuration.
     ///
     /// # Errors
     ///
     /// This function will error on memory allocation failure.
     pub fn new(environment: &Environment, stack_size: u32) -> Result<Self> {
+        Runtime::with_config(environment, stack_size, RuntimeConfig::default(), None)
+    }
+
+    /// Creates a builder for a runtime in the given environment.
+    pub fn builder(environment: &Environment) -> RuntimeBuilder {
+        RuntimeBuilder::new(environment)
+    }
+
+    pub(crate) fn with_config(
+        environment: &Environment,
+        stack_size: u32,
+        config: RuntimeConfig,
+        user_data: Option<Box<AnyData>>,
+    ) -> Result<Self> {
+        let mut state = RuntimeState::default();
+        state.user_data = user_data.map(|data| NonNull::from(Box::leak(data)));
+        let state = NonNull::from(Box::leak(Box::new(state)));
         unsafe {
             NonNull::new(ffi::m3_NewRuntime(
                 environment.as_ptr(),
                 stack_size,
-                ptr::null_mut(),
+                state.as_ptr().cast(),
             ))
         }
-        .ok_or_else(Error::malloc_error)
-        .map(|raw| Runtime {
-            raw,
-            environment: environment.clone(),
-            closure_store: UnsafeCell::new(Vec::new()),
-            module_data: UnsafeCell::new(Vec::new()),
+        .ok_or_else(|| {
+            drop(unsafe { Box::from_raw(state.as_ptr()) });
+            Error::malloc_error()
+        })
+        .map(|raw| {
+            unsafe {
+                (*raw.as_ptr()).memoryLimit = builder::memory_limit_bytes(config.memory_pages)
+            };
+            Runtime {
+                raw,
+                state,
+                environment: environment.clone(),
+                closure_store: UnsafeCell::new(Vec::new()),
+                module_data: UnsafeCell::new(Vec::new()),
+                module_ids: UnsafeCell::new(Vec::new()),
+                fuel_globals: UnsafeCell::new(Vec::new()),
+                module_hashes: UnsafeCell::new(Vec::new()),
+                uncompiled: UnsafeCell::new(Vec::new()),
+                config,
+                last_call_stats: Cell::new(None),
+                call_depth: Cell::new(0),
+                call_failed: Cell::new(false),
+            }
         })
     }
 
@@ -55,21 +190,121 @@ impl Runtime {
     ///
     /// # Errors
     ///
-    /// This function will error if the module's environment differs from the one this runtime uses.
+    /// This function will error if the module's environment differs from the one this runtime
+    /// uses or if the module exceeds the runtime's memory or table limits. Deterministic
+    /// runtimes fail with [`Error::Parse`] to load modules that can't be executed
+    /// deterministically.
     pub fn load_module(&self, module: ParsedModule) -> Result<Module> {
         if &self.environment != module.environment() {
             Err(Error::ModuleLoadEnvMismatch)
         } else {
+            self.check_limits(&module)?;
+            let module = if self.config.deterministic {
+                module.into_deterministic()?
+            } else {
+                module
+            };
+            let _lock = self.environment.lock()?;
             let raw_mod = module.as_ptr();
-            Error::from_ffi_res(unsafe { ffi::m3_LoadModule(self.raw.as_ptr(), raw_mod) })?;
-            // SAFETY: Runtime isn't Send, therefor this access is single-threaded and kept alive only for the Vec::push call
+            let metered = module.is_metered();
+            let interruptible = module.is_interruptible();
+            Error::from_runtime_res(self.as_ptr(), unsafe {
+                ffi::m3_LoadModule(self.raw.as_ptr(), raw_mod)
+            })?;
+            // SAFETY: Runtime isn't Sync, therefor this access is single-threaded and kept alive only for the Vec::push call
             // as such this can not alias.
-            unsafe { (*self.module_data.get()).push(module.take_data()) };
+            unsafe { (*self.module_data.get()).push((raw_mod, module.take_data())) };
+            // SAFETY: see above
+            unsafe { (*self.module_ids.get()).push((raw_mod, ModuleId::next())) };
+            if metered {
+                let global = unsafe { fuel::find_global(raw_mod) };
+                Error::from_runtime_res(self.as_ptr(), unsafe { fuel::link(raw_mod, global) })?;
+                // SAFETY: see above
+                unsafe { (*self.fuel_globals.get()).push((raw_mod, global)) };
+            }
+            if interruptible {
+                let global = unsafe { fuel::find_interrupt_global(raw_mod) };
+                self.interrupt().register(raw_mod, global);
+            }
+            // calls to imports only compile once they are linked, and with the `sync` feature,
+            // calls don't lock the environment and must not compile
+            if self.config.compile_eagerly || cfg!(feature = "sync") {
+                // SAFETY: see above
+                unsafe { (*self.uncompiled.get()).push(raw_mod) };
+            }
 
             Ok(Module::from_raw(self, raw_mod))
         }
     }
 
+    /// Loads a new instance of the given shared module into this runtime.
+    ///
+    /// # Errors
+    ///
+    /// See [`Runtime::load_module`].
+    pub fn load_shared_module(&self, module: &SharedModule) -> Result<Module> {
+        self.load_module(module.instantiate()?)
+    }
+
+    /// Unloads the module with the given identifier from this runtime, freeing the module, its
+    /// backing data and all closures that have been linked into it.
+    ///
+    /// As this borrows the runtime mutably, all [`Module`]s and [`Function`]s handed out by this
+    /// runtime have to be dropped beforehand and have to be looked up again afterwards, so the
+    /// module is identified by the [`ModuleId`] taken from it with [`Module::id`].
+    ///
+    /// ```ignore
+    /// let id = runtime.load_module(parsed)?.id();
+    /// runtime.unload_module(id)?;
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function will error if the module isn't loaded in this runtime, be it because it
+    /// has been unloaded already or belongs to another runtime.
+    pub fn unload_module(&mut self, module: ModuleId) -> Result<()> {
+        let raw_mod = self
+            .module_ids
+            .get_mut()
+            .iter()
+            .find(|&&(_, id)| id == module)
+            .map(|&(raw_mod, _)| raw_mod)
+            .ok_or(Error::ModuleNotFound)?;
+        unsafe {
+            let _lock = self.environment.lock()?;
+            let rt = self.raw.as_ptr();
+            // wasm3 keeps the loaded modules in a singly linked list, so the module has to be
+            // unlinked before it can be freed without the runtime freeing it a second time.
+            let mut link: *mut ffi::IM3Module = &mut (*rt).modules;
+            while *link != raw_mod {
+                link = &mut (**link).next;
+            }
+            *link = (*raw_mod).next;
+            (*rt).lastCalled = ptr::null_mut();
+            self.interrupt().unregister(raw_mod);
+            ffi::m3_FreeModule(raw_mod);
+        }
+        self.module_data
+            .get_mut()
+            .retain(|&(module, _)| module != raw_mod);
+        self.module_ids
+            .get_mut()
+            .retain(|&(module, _)| module != raw_mod);
+        self.closure_store
+            .get_mut()
+            .retain(|&(module, _)| module != raw_mod);
+        self.fuel_globals
+            .get_mut()
+            .retain(|&(module, _)| module != raw_mod);
+        self.module_hashes
+            .get_mut()
+            .retain(|&(module, _)| module != raw_mod);
+        self.uncompiled
+            .get_mut()
+            .retain(|&module| module != raw_mod);
+        Ok(())
+    }
+
     /// Looks up a function by the given name in the loaded modules of this runtime.
     /// See [`Module::find_function`] for possible error cases.
     ///
@@ -79,18 +314,242 @@ impl Runtime {
         ARGS: crate::WasmArgs,
         RET: crate::WasmType,
     {
-        let mut func_raw: ffi::IM3Function = core::ptr::null_mut();
-        let func_name_cstr = str_to_cstr_owned(name);
-        let result = unsafe {
-            ffi::m3_FindFunction(
-                &mut func_raw as *mut ffi::IM3Function,
-                self.as_ptr(),
-                func_name_cstr.as_ptr(),
-            )
-        };
-        Error::from_ffi_res(result)?;
-        let func = NonNull::new(func_raw).ok_or(Error::FunctionNotFound)?;
-        Function::from_raw(self, func)
+        Function::from_raw(self, self.find_raw_function(name)?)
+    }
+
+    /// Calls the function by the given name in the loaded modules of this runtime with
+    /// dynamically typed arguments, returning its results.
+    ///
+    /// # Errors
+    ///
+    /// This function will error if no function by the given name can be found, with
+    /// [`Error::InvalidFunctionSignature`] if the arguments don't match the function's
+    /// parameters, and with the error of the call if it fails.
+    pub fn call(&self, name: &str, args: &[Value]) -> Result<Vec<Value>> {
+        let func = self.find_raw_function(name)?.as_ptr();
+        let params = unsafe { ffi::m3_GetArgCount(func) };
+        if args.len() != params as usize
+            || (0..params)
+                .zip(args)
+                .any(|(i, arg)| arg.type_index() != unsafe { ffi::m3_GetArgType(func, i) })
+        {
+            return Err(Error::InvalidFunctionSignature);
+        }
+        let mut slots = alloc::vec![0u64; args.len()];
+        for (slot, arg) in slots.iter_mut().zip(args) {
+            unsafe { arg.write(slot) };
+        }
+        let mut arg_ptrs = slots
+            .iter()
+            .map(|slot| (slot as *const u64).cast())
+            .collect::<Vec<_>>();
+        let result =
+            self.track_call(|| unsafe { ffi::m3_Call(func, params, arg_ptrs.as_mut_ptr()) });
+        Error::from_call_res(self.as_ptr(), result)?;
+
+        let results = unsafe { ffi::m3_GetRetCount(func) };
+        let mut slots = alloc::vec![0u64; results as usize];
+        let mut ret_ptrs = slots
+            .iter_mut()
+            .map(|slot| (slot as *mut u64 as *const u64).cast())
+            .collect::<Vec<_>>();
+        Error::from_ffi_res(unsafe { ffi::m3_GetResults(func, results, ret_ptrs.as_mut_ptr()) })?;
+        (0..results)
+            .zip(slots.iter_mut())
+            .map(|(i, slot)| unsafe { Value::read(ffi::m3_GetRetType(func, i), slot) })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::InvalidFunctionSignature)
+    }
+
+    /// Clears the error information wasm3 recorded for the last failure in this runtime,
+    /// as well as any host error or panic that has not been returned from a call.
+    ///
+    /// This is not required for the runtime to keep serving calls, but makes sure no details
+    /// of an earlier failure are attached to later errors.
+    pub fn reset_error_state(&self) {
+        unsafe { ffi::m3_ResetErrorInfo(self.as_ptr()) };
+        #[cfg(feature = "std")]
+        {
+            // SAFETY: no call into wasm can be in progress while the runtime is borrowed here,
+            // so no trampoline holds a reference to the state.
+            let state = unsafe { &mut *self.state.as_ptr() };
+            state.host_error = None;
+            state.host_panic = None;
+        }
+    }
+
+    /// Sets the fuel available to calls into metered modules of this runtime, replacing any
+    /// fuel left over. Fuel is unlimited until this is called.
+    ///
+    /// See [`ParsedModule::parse_metered`] for how modules are metered.
+    pub fn set_fuel(&self, fuel: u64) {
+        // SAFETY: no call into wasm can be in progress while the runtime is borrowed here,
+        // so no hook holds a reference to the state.
+        unsafe { (*self.state.as_ptr()).fuel = Some(fuel) };
+        for &(_, global) in unsafe { &*self.fuel_globals.get() } {
+            unsafe { fuel::set_fuel(global, 0) };
+        }
+    }
+
+    /// Returns the fuel left for calls into metered modules of this runtime, or `None` if fuel
+    /// is unlimited.
+    pub fn fuel_remaining(&self) -> Option<u64> {
+        let reserve = unsafe { (*self.state.as_ptr()).fuel }?;
+        let handed_out = unsafe { &*self.fuel_globals.get() }
+            .iter()
+            .map(|&(_, global)| unsafe { fuel::get_fuel(global) }.max(0) as u64)
+            .sum::<u64>();
+        Some(reserve + handed_out)
+    }
+
+    /// Returns the resources used by the last call into this runtime, if the runtime has been
+    /// built to record them with [`RuntimeBuilder::record_call_stats`].
+    pub fn last_call_stats(&self) -> Option<CallStats> {
+        self.last_call_stats.get()
+    }
+
+    /// Returns a reference to the data attached with [`RuntimeBuilder::user_data`], if it is of
+    /// type `T`.
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        unsafe { (*self.state.as_ptr()).user_data.map(|data| &*data.as_ptr()) }
+            .and_then(|data| data.downcast_ref())
+    }
+
+    /// Returns a mutable reference to the data attached with [`RuntimeBuilder::user_data`], if it
+    /// is of type `T`.
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        unsafe {
+            (*self.state.as_ptr())
+                .user_data
+                .map(|data| &mut *data.as_ptr())
+        }
+        .and_then(|data| data.downcast_mut())
+    }
+
+    /// Whether this runtime executes modules deterministically, see
+    /// [`RuntimeBuilder::deterministic`].
+    ///
+    /// [`RuntimeBuilder::deterministic`]: crate::RuntimeBuilder::deterministic
+    pub fn is_deterministic(&self) -> bool {
+        self.config.deterministic
+    }
+
+    /// Sets a callback which is run periodically during calls into this runtime, whenever wasm3
+    /// yields. It can be used to report progress or to cancel long running calls, which then
+    /// trap with [`Trap::Cancelled`] if the callback returns [`YieldAction::Abort`]. A callback
+    /// that panics fails the call like a panicking host function does.
+    ///
+    /// wasm3 yields on every call of a wasm function, but not on loop iterations, so a loop
+    /// that calls no functions can't be cancelled this way. Use [`InterruptHandle`] to stop
+    /// those.
+    ///
+    /// As the callback runs very often, it should return quickly.
+    ///
+    /// [`Trap::Cancelled`]: crate::error::Trap::Cancelled
+    #[cfg(feature = "yield-callback")]
+    pub fn set_yield_callback<F>(&self, callback: F)
+    where
+        F: FnMut() -> YieldAction + MaybeSend + 'static,
+    {
+        unsafe { (*self.state.as_ptr()).yield_callback = Some(Box::new(callback)) };
+    }
+
+    /// Removes the callback set with [`Runtime::set_yield_callback`].
+    #[cfg(feature = "yield-callback")]
+    pub fn clear_yield_callback(&self) {
+        unsafe { (*self.state.as_ptr()).yield_callback = None };
+    }
+
+    /// Returns a handle through which calls into this runtime can be interrupted from other
+    /// threads.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.interrupt().clone())
+    }
+
+    /// Runs the given call into this runtime, interrupting it if it takes longer than the
+    /// given timeout. Calls are timed by a single thread shared by all runtimes, which is
+    /// spawned on first use.
+    ///
+    /// ```ignore
+    /// let result = runtime.call_with_timeout(Duration::from_millis(100), || func.call(1, 2));
+    /// ```
+    ///
+    /// See [`InterruptHandle`] for which modules observe the interrupt.
+    #[cfg(feature = "std")]
+    pub fn call_with_timeout<R>(
+        &self,
+        timeout: Duration,
+        call: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        let deadline = watchdog::arm(timeout, self.interrupt());
+        let result = call();
+        // the deadline may have passed after the call returned, which must not affect the
+        // next call
+        if deadline.is_some_and(watchdog::disarm) {
+            self.interrupt().cancel_timeout();
+        }
+        result
+    }
+
+    /// Takes a snapshot of the state of this runtime's linear memory and mutable globals.
+    ///
+    /// ```ignore
+    /// module.find_function::<(), ()>("init")?.call()?;
+    /// let initialized = runtime.snapshot();
+    /// // before each use
+    /// runtime.restore(&initialized)?;
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        unsafe { Snapshot::capture(self) }
+    }
+
+    /// Puts the state of linear memory and mutable globals back to how it was when the given
+    /// snapshot was taken, resizing memory if it has grown or shrunk since.
+    ///
+    /// The snapshot may have been taken of another runtime, as long as the same modules have been
+    /// loaded into both in the same order. Fuel is not part of a snapshot and stays untouched.
+    ///
+    /// # Errors
+    ///
+    /// This function will error if the loaded modules differ from those of the runtime the
+    /// snapshot was taken of, leaving the runtime as it was, or if memory can't be resized.
+    pub fn restore(&self, snapshot: &Snapshot) -> Result<()> {
+        unsafe { snapshot.restore(self) }
+    }
+
+    /// Returns the indices of the 64KiB pages of linear memory whose contents differ from the
+    /// given snapshot, including pages the memory has grown by since the snapshot was taken.
+    ///
+    /// # Errors
+    ///
+    /// This function will error if the loaded modules differ from those of the runtime the
+    /// snapshot was taken of.
+    pub fn dirty_pages(&self, baseline: &Snapshot) -> Result<Vec<u32>> {
+        unsafe { baseline.dirty_pages(self) }
+    }
+
+    /// Resets linear memory and mutable globals to the given snapshot like [`Runtime::restore`]
+    /// does, but only copies back the pages of memory that differ from it, returning how many
+    /// did.
+    ///
+    /// Pages are compared with hashes of the snapshot's pages, computed when it was taken,
+    /// rather than tracked as they are written, so that writes by host functions are caught as
+    /// well and no instrumentation slows down calls. As a call usually touches few of the pages,
+    /// only few are copied back.
+    ///
+    /// ```ignore
+    /// let baseline = runtime.snapshot();
+    /// loop {
+    ///     handle.call(request)?;
+    ///     runtime.reset_dirty_pages(&baseline)?;
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// See [`Runtime::restore`].
+    pub fn reset_dirty_pages(&self, baseline: &Snapshot) -> Result<usize> {
+        unsafe { baseline.reset_dirty_pages(self) }
     }
 
     /// Returns the raw memory of this runtime.
@@ -117,18 +576,182 @@ impl Runtime {
 }
 
 impl Runtime {
-    pub(crate) fn push_closure(&self, closure: PinnedAnyClosure) {
-        unsafe { (*self.closure_store.get()).push(closure) };
+    fn check_limits(&self, module: &ParsedModule) -> Result<()> {
+        let config = &self.config;
+        if config.memory_pages.is_none() && config.table_elements.is_none() {
+            return Ok(());
+        }
+        let sizes = binary::initial_sizes(module.data()).map_err(Error::Parse)?;
+        if matches!(config.memory_pages, Some(pages) if sizes.memory_pages > pages) {
+            return Err(Error::MemoryLimitExceeded);
+        }
+        if matches!(config.table_elements, Some(elements) if sizes.table_elements > elements) {
+            return Err(Error::TableLimitExceeded);
+        }
+        Ok(())
+    }
+
+    /// Performs a call into wasm, recording its resource usage if configured to.
+    pub(crate) fn track_call(&self, call: impl FnOnce() -> ffi::M3Result) -> ffi::M3Result {
+        // an exit code not picked up by a failed nested call must not be attributed to this one
+        #[cfg(feature = "wasi")]
+        unsafe {
+            (*self.state.as_ptr()).exit_code = None
+        };
+        #[cfg(feature = "yield-callback")]
+        let _current = yield_callback::enter(self.as_ptr());
+        // calls made by host functions are part of the outermost call, whose frames are live
+        // on the stack the tracker would overwrite
+        let depth = self.call_depth.get();
+        self.call_depth.set(depth + 1);
+        let mut result = if self.config.record_call_stats && depth == 0 {
+            let tracker = unsafe { CallTracker::start(self.as_ptr()) };
+            let result = call();
+            self.last_call_stats
+                .set(Some(unsafe { tracker.finish(self.as_ptr()) }));
+            result
+        } else {
+            call()
+        };
+        self.call_depth.set(depth);
+        // interrupted calls trap on the `unreachable` of their interrupt check, a guest
+        // reaching a genuine `unreachable` while an interrupt is pending and an interruptible
+        // module is loaded is reported as interrupted as well
+        if result == unsafe { ffi::m3Err_trapUnreachable } && self.interrupt().take_pending() {
+            result = Trap::Interrupted.as_ptr();
+        }
+        if !result.is_null() {
+            self.call_failed.set(true);
+        }
+        result
+    }
+
+    /// Returns whether a call into the runtime failed since the last time this was called.
+    #[cfg(feature = "std")]
+    pub(crate) fn take_call_failed(&self) -> bool {
+        self.call_failed.replace(false)
+    }
+
+    /// Returns whether the given global has been added by instrumentation rather than by the
+    /// guest.
+    pub(crate) fn is_internal_global(&self, global: ffi::IM3Global) -> bool {
+        unsafe { &*self.fuel_globals.get() }
+            .iter()
+            .any(|&(_, fuel_global)| fuel_global == global)
+            || self.interrupt().contains(global)
+    }
+
+    /// Returns the hash of the backing data of the given loaded module.
+    pub(crate) fn module_hash(&self, module: ffi::IM3Module) -> u64 {
+        // SAFETY: Runtime isn't Sync and none of these references outlive this function
+        let hashes = unsafe { &mut *self.module_hashes.get() };
+        if let Some(&(_, hash)) = hashes.iter().find(|&&(raw_mod, _)| raw_mod == module) {
+            return hash;
+        }
+        let data = unsafe { &*self.module_data.get() }
+            .iter()
+            .find(|&&(raw_mod, _)| raw_mod == module)
+            .map_or(&[][..], |(_, data)| &**data);
+        let mut hasher = Fnv1a::default();
+        hasher.write(data);
+        hashes.push((module, hasher.finish()));
+        hasher.finish()
+    }
+
+    pub(crate) fn push_closure(&self, module: ffi::IM3Module, closure: PinnedAnyClosure) {
+        unsafe { (*self.closure_store.get()).push((module, closure)) };
+    }
+
+    fn find_raw_function(&self, name: &str) -> Result<NNM3Function> {
+        let mut func_raw: ffi::IM3Function = core::ptr::null_mut();
+        let func_name_cstr = str_to_cstr_owned(name);
+        let result = {
+            // wasm3 compiles functions when they are looked up, and runs start functions
+            let _lock = self.environment.lock()?;
+            let result = unsafe {
+                ffi::m3_FindFunction(
+                    &mut func_raw as *mut ffi::IM3Function,
+                    self.as_ptr(),
+                    func_name_cstr.as_ptr(),
+                )
+            };
+            if result.is_null() && !func_raw.is_null() {
+                self.compile_module(unsafe { ffi::m3_GetFunctionModule(func_raw) })
+            } else {
+                result
+            }
+        };
+        Error::from_runtime_res(self.as_ptr(), result)?;
+        NonNull::new(func_raw).ok_or(Error::FunctionNotFound)
+    }
+
+    /// Compiles all functions of the given module if it is still to be compiled as a whole,
+    /// which requires the environment to be locked.
+    fn compile_module(&self, module: ffi::IM3Module) -> ffi::M3Result {
+        // SAFETY: Runtime isn't Sync and compiling doesn't run any code that could access the
+        // list as well
+        let uncompiled = unsafe { &mut *self.uncompiled.get() };
+        match uncompiled.iter().position(|&raw_mod| raw_mod == module) {
+            Some(index) => {
+                let result = unsafe { ffi::m3_CompileModule(module) };
+                // a module failing to compile is compiled again on the next lookup, as the
+                // imports it misses may have been linked by then
+                if result.is_null() {
+                    uncompiled.swap_remove(index);
+                }
+                result
+            }
+            None => ptr::null(),
+        }
+    }
+
+    /// Returns the identifier of the given loaded module.
+    pub(crate) fn module_id(&self, module: ffi::IM3Module) -> ModuleId {
+        unsafe { &*self.module_ids.get() }
+            .iter()
+            .find(|&&(raw_mod, _)| raw_mod == module)
+            .map(|&(_, id)| id)
+            .expect("modules are given an identifier when they are loaded")
     }
 
     pub(crate) fn as_ptr(&self) -> ffi::IM3Runtime {
         self.raw.as_ptr()
     }
+
+    fn interrupt(&self) -> &Arc<Interrupt> {
+        // SAFETY: the interrupt is never replaced, so this doesn't alias a mutable access
+        unsafe { &(*self.state.as_ptr()).interrupt }
+    }
+
+    pub(crate) fn lock_environment(&self) -> Result<EnvironmentLock<'_>> {
+        self.environment.lock()
+    }
 }
 
+// SAFETY: the runtime is not `Sync`, so its interior mutability is never accessed from two
+// threads at once, and everything it owns is `Send` with the `sync` feature enabled
+#[cfg(feature = "sync")]
+unsafe impl Send for Runtime {}
+
 impl Drop for Runtime {
     fn drop(&mut self) {
-        unsafe { ffi::m3_FreeRuntime(self.raw.as_ptr()) };
+        // the runtime's code pages are released into the environment
+        let _lock = match self.environment.lock() {
+            Ok(lock) => Some(lock),
+            // the current thread holds the lock already if this runtime is dropped by a host
+            // function called by a start function. The lock still keeps other threads out, and
+            // wasm3 doesn't touch the environment while the start function runs, so the pages
+            // can be released without deadlocking on the lock.
+            #[cfg(feature = "sync")]
+            Err(Error::EnvironmentLocked) => None,
+            Err(err) => unreachable!("failed to lock the environment: {}", err),
+        };
+        // interrupt handles may outlive the runtime and must not touch its globals anymore
+        self.interrupt().unregister_all();
+        unsafe {
+            ffi::m3_FreeRuntime(self.raw.as_ptr());
+            drop(Box::from_raw(self.state.as_ptr()));
+        }
     }
 }
 
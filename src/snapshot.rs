@@ -0,0 +1,513 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::hash::Hasher;
+use core::slice;
+use core::str;
+
+use crate::builder;
+use crate::error::{Error, Result};
+use crate::runtime::Runtime;
+use crate::ty::Value;
+use crate::utils::{cstr_to_str, Fnv1a};
+
+// the layout of serialized snapshots, all integers are little endian:
+//
+//   magic           8 bytes, MAGIC
+//   format version  u32, FORMAT_VERSION
+//   crate version   u32 length, UTF-8
+//   memory pages    u32
+//   modules         u32 count, each:
+//     name          u32 length, UTF-8
+//     hash          u64, FNV-1a of the module's backing data
+//     globals       u32 count, each a u8 value type followed by the value's bits as u64
+//   memory          u64 length, bytes
+//   checksum        u64, FNV-1a of everything before
+const MAGIC: &[u8; 8] = b"wasm3snp";
+const FORMAT_VERSION: u32 = 1;
+const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const PAGE_SIZE: usize = builder::PAGE_SIZE as usize;
+
+/// The state of a runtime's linear memory and mutable globals, taken with [`Runtime::snapshot`]
+/// and put back with [`Runtime::restore`].
+///
+/// This allows running a module's initialization once and resetting the runtime to the
+/// initialized state before each use, which is much cheaper than loading the module again.
+///
+/// Snapshots can be serialized with [`Snapshot::to_bytes`], for example to store the
+/// initialized state on disk and restore it in another process.
+///
+/// [`Runtime::snapshot`]: crate::Runtime::snapshot
+/// [`Runtime::restore`]: crate::Runtime::restore
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    memory_pages: u32,
+    memory: Box<[u8]>,
+    // in the order wasm3 keeps the runtime's modules in, which is the reverse load order
+    modules: Vec<ModuleSnapshot>,
+    // hashes of the memory's pages, against which the pages of a runtime are compared to find
+    // the dirty ones, keyed so that a guest can't make a dirty page hash like the clean one
+    hash_key: u64,
+    page_hashes: Box<[u64]>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ModuleSnapshot {
+    name: String,
+    hash: u64,
+    // the values of the module's own mutable globals in declaration order
+    globals: Vec<Value>,
+}
+
+impl Snapshot {
+    fn new(memory_pages: u32, memory: Box<[u8]>, modules: Vec<ModuleSnapshot>) -> Snapshot {
+        let hash_key = hash_key();
+        let page_hashes = memory
+            .chunks(PAGE_SIZE)
+            .map(|page| page_hash(hash_key, page))
+            .collect();
+        Snapshot {
+            memory_pages,
+            memory,
+            modules,
+            hash_key,
+            page_hashes,
+        }
+    }
+
+    /// The size of the linear memory in 64KiB pages.
+    pub fn memory_pages(&self) -> u32 {
+        self.memory_pages
+    }
+
+    /// The contents of the linear memory.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Serializes this snapshot into a versioned binary format, which is read back by
+    /// [`Snapshot::from_bytes`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.memory.len() + 256);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        write_str(&mut out, CRATE_VERSION);
+        out.extend_from_slice(&self.memory_pages.to_le_bytes());
+        out.extend_from_slice(&(self.modules.len() as u32).to_le_bytes());
+        for module in &self.modules {
+            write_str(&mut out, &module.name);
+            out.extend_from_slice(&module.hash.to_le_bytes());
+            out.extend_from_slice(&(module.globals.len() as u32).to_le_bytes());
+            for global in &module.globals {
+                let bits = match *global {
+                    Value::I32(value) => value as u32 as u64,
+                    Value::I64(value) => value as u64,
+                    Value::F32(value) => value.to_bits() as u64,
+                    Value::F64(value) => value.to_bits(),
+                };
+                out.push(global.type_index() as u8);
+                out.extend_from_slice(&bits.to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&(self.memory.len() as u64).to_le_bytes());
+        out.extend_from_slice(&self.memory);
+        let mut checksum = Fnv1a::default();
+        checksum.write(&out);
+        out.extend_from_slice(&checksum.finish().to_le_bytes());
+        out
+    }
+
+    /// Deserializes a snapshot serialized with [`Snapshot::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// This function will error if the bytes are not a snapshot, are corrupted or have been
+    /// written by another version of this crate.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidSnapshot("not a snapshot"));
+        }
+        if bytes.len() < MAGIC.len() + 8 {
+            return Err(Error::InvalidSnapshot("unexpected end of snapshot"));
+        }
+        let (contents, checksum) = bytes.split_at(bytes.len() - 8);
+        let mut hasher = Fnv1a::default();
+        hasher.write(contents);
+        if hasher.finish().to_le_bytes() != checksum {
+            return Err(Error::InvalidSnapshot("checksum mismatch"));
+        }
+
+        let mut reader = SnapshotReader(&contents[MAGIC.len()..]);
+        if reader.u32()? != FORMAT_VERSION || reader.str()? != CRATE_VERSION {
+            return Err(Error::InvalidSnapshot(
+                "written by another version of wasm3-rs",
+            ));
+        }
+        let memory_pages = reader.u32()?;
+        let modules = (0..reader.u32()?)
+            .map(|_| {
+                let name = reader.str()?.to_string();
+                let hash = reader.u64()?;
+                let globals = (0..reader.u32()?)
+                    .map(|_| {
+                        let ty = reader.bytes(1)?[0];
+                        let bits = reader.u64()?;
+                        match ffi::M3ValueType::Type::from(ty) {
+                            ffi::M3ValueType::c_m3Type_i32 => Ok(Value::I32(bits as i32)),
+                            ffi::M3ValueType::c_m3Type_i64 => Ok(Value::I64(bits as i64)),
+                            ffi::M3ValueType::c_m3Type_f32 => {
+                                Ok(Value::F32(f32::from_bits(bits as u32)))
+                            }
+                            ffi::M3ValueType::c_m3Type_f64 => Ok(Value::F64(f64::from_bits(bits))),
+                            _ => Err(Error::InvalidSnapshot("invalid global type")),
+                        }
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(ModuleSnapshot {
+                    name,
+                    hash,
+                    globals,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let memory_len = reader.u64()?;
+        let memory = reader.bytes(memory_len.try_into().unwrap_or(usize::MAX))?;
+        if !reader.0.is_empty() {
+            return Err(Error::InvalidSnapshot("trailing bytes"));
+        }
+        if memory_len != u64::from(memory_pages) * PAGE_SIZE as u64 {
+            return Err(Error::InvalidSnapshot("memory size mismatch"));
+        }
+        Ok(Snapshot::new(memory_pages, memory.into(), modules))
+    }
+
+    /// # Safety
+    ///
+    /// The runtime must not be executing a call.
+    pub(crate) unsafe fn capture(runtime: &Runtime) -> Snapshot {
+        let raw = runtime.as_ptr();
+        let mut modules = Vec::new();
+        let mut module = (*raw).modules;
+        while !module.is_null() {
+            modules.push(ModuleSnapshot {
+                name: cstr_to_str(ffi::m3_GetModuleName(module)).to_string(),
+                hash: runtime.module_hash(module),
+                globals: snapshot_globals(runtime, module)
+                    .map(|global| get_global(global))
+                    .collect(),
+            });
+            module = (*module).next;
+        }
+        // wasm3 lets memory grow past the runtime's memory limit without backing the pages
+        // beyond it, which are left out so that the snapshot's memory spans its pages exactly
+        let memory = memory(raw);
+        Snapshot::new((memory.len() / PAGE_SIZE) as u32, memory.into(), modules)
+    }
+
+    /// # Safety
+    ///
+    /// See [`Snapshot::capture`].
+    pub(crate) unsafe fn restore(&self, runtime: &Runtime) -> Result<()> {
+        self.check(runtime)?;
+        let memory = self.resize_memory(runtime.as_ptr())?;
+        let (restored, rest) = memory.split_at_mut(self.memory.len());
+        restored.copy_from_slice(&self.memory);
+        rest.fill(0);
+        self.restore_globals(runtime);
+        Ok(())
+    }
+
+    /// Like [`Snapshot::restore`], but only copies back the pages that differ, returning how
+    /// many did.
+    ///
+    /// # Safety
+    ///
+    /// See [`Snapshot::capture`].
+    pub(crate) unsafe fn reset_dirty_pages(&self, runtime: &Runtime) -> Result<usize> {
+        self.check(runtime)?;
+        let memory = self.resize_memory(runtime.as_ptr())?;
+        let mut reset = 0;
+        for (idx, page) in memory.chunks_mut(PAGE_SIZE).enumerate() {
+            if !self.is_dirty(idx, page) {
+                continue;
+            }
+            match self.memory.chunks(PAGE_SIZE).nth(idx) {
+                Some(baseline) => page.copy_from_slice(baseline),
+                None => page.fill(0),
+            }
+            reset += 1;
+        }
+        self.restore_globals(runtime);
+        Ok(reset)
+    }
+
+    /// # Safety
+    ///
+    /// See [`Snapshot::capture`].
+    pub(crate) unsafe fn dirty_pages(&self, runtime: &Runtime) -> Result<Vec<u32>> {
+        self.check(runtime)?;
+        Ok(memory(runtime.as_ptr())
+            .chunks(PAGE_SIZE)
+            .enumerate()
+            .filter(|&(idx, page)| self.is_dirty(idx, page))
+            .map(|(idx, _)| idx as u32)
+            .collect())
+    }
+
+    // whether the page at the given index differs from the snapshot's, which it does if the
+    // memory has grown by it since
+    fn is_dirty(&self, idx: usize, page: &[u8]) -> bool {
+        self.page_hashes.get(idx) != Some(&page_hash(self.hash_key, page))
+    }
+
+    // checks that the runtime has the modules loaded that the snapshot was taken of
+    unsafe fn check(&self, runtime: &Runtime) -> Result<()> {
+        let mut module = (*runtime.as_ptr()).modules;
+        for snapshot in &self.modules {
+            if module.is_null()
+                || cstr_to_str(ffi::m3_GetModuleName(module)) != snapshot.name
+                || runtime.module_hash(module) != snapshot.hash
+                || !snapshot_globals(runtime, module)
+                    .map(|global| ffi::m3_GetGlobalType(global))
+                    .eq(snapshot.globals.iter().map(Value::type_index))
+            {
+                return Err(Error::SnapshotMismatch);
+            }
+            module = (*module).next;
+        }
+        if module.is_null() {
+            Ok(())
+        } else {
+            Err(Error::SnapshotMismatch)
+        }
+    }
+
+    // resizes the runtime's memory to the snapshot's size, returning the resized memory
+    unsafe fn resize_memory<'rt>(&self, runtime: ffi::IM3Runtime) -> Result<&'rt mut [u8]> {
+        if (*runtime).memory.numPages != self.memory_pages {
+            Error::from_runtime_res(runtime, ffi::ResizeMemory(runtime, self.memory_pages))?;
+        }
+        let memory = memory(runtime);
+        // the runtime's memory limit may cap the memory below the snapshot's size
+        if memory.len() < self.memory.len() {
+            return Err(Error::MemoryLimitExceeded);
+        }
+        Ok(memory)
+    }
+
+    unsafe fn restore_globals(&self, runtime: &Runtime) {
+        let mut module = (*runtime.as_ptr()).modules;
+        for snapshot in &self.modules {
+            for (global, &value) in snapshot_globals(runtime, module).zip(&snapshot.globals) {
+                set_global(global, value);
+            }
+            module = (*module).next;
+        }
+    }
+}
+
+// compares the contents rather than the hashes, which depend on a random key
+impl PartialEq for Snapshot {
+    fn eq(&self, other: &Snapshot) -> bool {
+        self.memory_pages == other.memory_pages
+            && self.memory == other.memory
+            && self.modules == other.modules
+    }
+}
+
+fn hash_key() -> u64 {
+    #[cfg(feature = "std")]
+    {
+        use std::hash::BuildHasher;
+        std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish()
+    }
+    #[cfg(not(feature = "std"))]
+    {
+        0x243f_6a88_85a3_08d3
+    }
+}
+
+// hashes a page word by word, which is much faster than FNV-1a going byte by byte
+fn page_hash(key: u64, page: &[u8]) -> u64 {
+    let mut words = page.chunks_exact(8);
+    let hash = words.by_ref().fold(key, |hash, word| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(word);
+        (hash.rotate_left(5) ^ u64::from_le_bytes(bytes)).wrapping_mul(0x517c_c1b7_2722_0a95)
+    });
+    words.remainder().iter().fold(hash, |hash, &byte| {
+        (hash.rotate_left(5) ^ u64::from(byte)).wrapping_mul(0x517c_c1b7_2722_0a95)
+    })
+}
+
+fn write_str(out: &mut Vec<u8>, str: &str) {
+    out.extend_from_slice(&(str.len() as u32).to_le_bytes());
+    out.extend_from_slice(str.as_bytes());
+}
+
+struct SnapshotReader<'b>(&'b [u8]);
+
+impl<'b> SnapshotReader<'b> {
+    fn bytes(&mut self, len: usize) -> Result<&'b [u8]> {
+        if len > self.0.len() {
+            return Err(Error::InvalidSnapshot("unexpected end of snapshot"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn str(&mut self) -> Result<&'b str> {
+        let len = self.u32()?;
+        str::from_utf8(self.bytes(len as usize)?)
+            .map_err(|_| Error::InvalidSnapshot("invalid UTF-8"))
+    }
+}
+
+unsafe fn memory<'rt>(runtime: ffi::IM3Runtime) -> &'rt mut [u8] {
+    let mut len: u32 = 0;
+    let data = ffi::m3_GetMemory(runtime, &mut len, 0);
+    if data.is_null() {
+        &mut []
+    } else {
+        slice::from_raw_parts_mut(data, len as usize)
+    }
+}
+
+// the mutable globals defined by the guest itself, as imported ones are owned by other modules
+// and metering adds one for fuel
+unsafe fn snapshot_globals(
+    runtime: &Runtime,
+    module: ffi::IM3Module,
+) -> impl Iterator<Item = ffi::IM3Global> + '_ {
+    let globals = (*module).globals;
+    (0..(*module).numGlobals as usize)
+        .map(move |idx| globals.add(idx))
+        .filter(move |&global| {
+            !(*global).imported && (*global).isMutable && !runtime.is_internal_global(global)
+        })
+}
+
+unsafe fn get_global(global: ffi::IM3Global) -> Value {
+    let mut value = ffi::M3TaggedValue {
+        type_: ffi::M3ValueType::c_m3Type_none,
+        value: ffi::M3TaggedValue_M3ValueUnion { i64_: 0 },
+    };
+    ffi::m3_GetGlobal(global, &mut value);
+    match value.type_ {
+        ffi::M3ValueType::c_m3Type_i32 => Value::I32(value.value.i32_ as i32),
+        ffi::M3ValueType::c_m3Type_i64 => Value::I64(value.value.i64_ as i64),
+        ffi::M3ValueType::c_m3Type_f32 => Value::F32(value.value.f32_),
+        ffi::M3ValueType::c_m3Type_f64 => Value::F64(value.value.f64_),
+        _ => unreachable!("wasm3 only supports globals of numeric types"),
+    }
+}
+
+unsafe fn set_global(global: ffi::IM3Global, value: Value) {
+    let raw = match value {
+        Value::I32(value) => ffi::M3TaggedValue_M3ValueUnion { i32_: value as u32 },
+        Value::I64(value) => ffi::M3TaggedValue_M3ValueUnion { i64_: value as u64 },
+        Value::F32(value) => ffi::M3TaggedValue_M3ValueUnion { f32_: value },
+        Value::F64(value) => ffi::M3TaggedValue_M3ValueUnion { f64_: value },
+    };
+    let value = ffi::M3TaggedValue {
+        type_: value.type_index(),
+        value: raw,
+    };
+    ffi::m3_SetGlobal(global, &value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn snapshot() -> Snapshot {
+        Snapshot::new(
+            1,
+            vec![7; 64 * 1024].into(),
+            vec![ModuleSnapshot {
+                name: "plugin".to_string(),
+                hash: 0x1234_5678_9abc_def0,
+                globals: vec![
+                    Value::I32(-1),
+                    Value::I64(42),
+                    Value::F32(1.5),
+                    Value::F64(-0.25),
+                ],
+            }],
+        )
+    }
+
+    #[test]
+    fn test_serialize_roundtrip() {
+        let snapshot = snapshot();
+        assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()), Ok(snapshot));
+    }
+
+    #[test]
+    fn test_dirty_pages() {
+        let snapshot = snapshot();
+        let mut page = snapshot.memory().to_vec();
+        assert!(!snapshot.is_dirty(0, &page));
+        page[1000] ^= 1;
+        assert!(snapshot.is_dirty(0, &page));
+        // pages the memory has grown by are dirty even if they are zeroed
+        assert!(snapshot.is_dirty(1, &[0; 64 * 1024]));
+    }
+
+    #[test]
+    fn test_deserialize_invalid() {
+        let bytes = snapshot().to_bytes();
+        assert_eq!(
+            Snapshot::from_bytes(b"\0asm\x01\0\0\0"),
+            Err(Error::InvalidSnapshot("not a snapshot"))
+        );
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..12]),
+            Err(Error::InvalidSnapshot("unexpected end of snapshot"))
+        );
+        for len in [20, bytes.len() - 1] {
+            assert_eq!(
+                Snapshot::from_bytes(&bytes[..len]),
+                Err(Error::InvalidSnapshot("checksum mismatch"))
+            );
+        }
+        let mut corrupted = bytes;
+        corrupted[100] ^= 1;
+        assert_eq!(
+            Snapshot::from_bytes(&corrupted),
+            Err(Error::InvalidSnapshot("checksum mismatch"))
+        );
+
+        // consistently checksummed, but with less memory than its pages span
+        let mut inconsistent = snapshot();
+        inconsistent.memory_pages = 2;
+        assert_eq!(
+            Snapshot::from_bytes(&inconsistent.to_bytes()),
+            Err(Error::InvalidSnapshot("memory size mismatch"))
+        );
+        inconsistent.memory_pages = 1;
+        inconsistent.memory = vec![7; 1000].into();
+        assert_eq!(
+            Snapshot::from_bytes(&inconsistent.to_bytes()),
+            Err(Error::InvalidSnapshot("memory size mismatch"))
+        );
+    }
+}
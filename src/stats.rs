@@ -0,0 +1,97 @@
+use core::slice;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::builder::SLOT_SIZE;
+use crate::runtime::RuntimeState;
+
+// written over the wasm stack before a call to find the part the call used afterwards
+const STACK_FILL: u32 = 0xa5a5_a5a5;
+
+/// The resources used by a call into wasm, as returned by [`Runtime::last_call_stats`].
+///
+/// [`Runtime::last_call_stats`]: crate::Runtime::last_call_stats
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallStats {
+    peak_stack_bytes: usize,
+    memory_growth: usize,
+    host_calls: u64,
+    #[cfg(feature = "std")]
+    wall_time: Duration,
+}
+
+impl CallStats {
+    /// The highest number of bytes of the wasm stack in use at any point during the call.
+    pub fn peak_stack_bytes(&self) -> usize {
+        self.peak_stack_bytes
+    }
+
+    /// The number of bytes the linear memory has grown by during the call.
+    pub fn memory_growth(&self) -> usize {
+        self.memory_growth
+    }
+
+    /// The number of calls made to host functions linked with [`Module::link_closure`] or
+    /// created with [`make_func_wrapper`].
+    ///
+    /// [`Module::link_closure`]: crate::Module::link_closure
+    /// [`make_func_wrapper`]: crate::make_func_wrapper
+    pub fn host_calls(&self) -> u64 {
+        self.host_calls
+    }
+
+    /// The time the call took.
+    #[cfg(feature = "std")]
+    pub fn wall_time(&self) -> Duration {
+        self.wall_time
+    }
+}
+
+/// Measures the resources used by a single call.
+pub(crate) struct CallTracker {
+    memory_size: u32,
+    host_calls: u64,
+    #[cfg(feature = "std")]
+    start: Instant,
+}
+
+impl CallTracker {
+    /// # Safety
+    ///
+    /// No call may be in progress in the given runtime.
+    pub(crate) unsafe fn start(runtime: ffi::IM3Runtime) -> Self {
+        stack_words(runtime).fill(STACK_FILL);
+        CallTracker {
+            memory_size: ffi::m3_GetMemorySize(runtime),
+            host_calls: RuntimeState::from_raw(runtime).host_calls,
+            #[cfg(feature = "std")]
+            start: Instant::now(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The call started with [`CallTracker::start`] has to have returned.
+    pub(crate) unsafe fn finish(self, runtime: ffi::IM3Runtime) -> CallStats {
+        #[cfg(feature = "std")]
+        let wall_time = self.start.elapsed();
+        let stack = stack_words(runtime);
+        let used = stack
+            .iter()
+            .rposition(|&word| word != STACK_FILL)
+            .map_or(0, |index| index + 1);
+        CallStats {
+            peak_stack_bytes: used * 4,
+            memory_growth: ffi::m3_GetMemorySize(runtime).saturating_sub(self.memory_size) as usize,
+            host_calls: RuntimeState::from_raw(runtime).host_calls - self.host_calls,
+            #[cfg(feature = "std")]
+            wall_time,
+        }
+    }
+}
+
+// the stack wasm3 allocated for the runtime, viewed as 32 bit words regardless of the slot size
+unsafe fn stack_words<'a>(runtime: ffi::IM3Runtime) -> &'a mut [u32] {
+    let len = (*runtime).numStackSlots as usize * (SLOT_SIZE as usize / 4);
+    slice::from_raw_parts_mut((*runtime).stack.cast::<u32>(), len)
+}
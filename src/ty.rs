@@ -286,9 +286,89 @@ macro_rules! args_impl {
 }
 args_impl!(A, B, C, D, E, F, G, H, J, K, L, M, N, O, P, Q);
 
+/// A dynamically typed wasm value.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Value {
+    /// A 32 bit integer.
+    I32(i32),
+    /// A 64 bit integer.
+    I64(i64),
+    /// A 32 bit float.
+    F32(f32),
+    /// A 64 bit float.
+    F64(f64),
+}
+
+impl Value {
+    pub(crate) fn type_index(&self) -> ffi::M3ValueType::Type {
+        match self {
+            Value::I32(_) => i32::TYPE_INDEX,
+            Value::I64(_) => i64::TYPE_INDEX,
+            Value::F32(_) => f32::TYPE_INDEX,
+            Value::F64(_) => f64::TYPE_INDEX,
+        }
+    }
+
+    /// Writes the value into a slot of 8 bytes.
+    pub(crate) unsafe fn write(self, slot: *mut u64) {
+        match self {
+            Value::I32(value) => WasmType::push_on_stack(value, slot),
+            Value::I64(value) => WasmType::push_on_stack(value, slot),
+            Value::F32(value) => WasmType::push_on_stack(value, slot),
+            Value::F64(value) => WasmType::push_on_stack(value, slot),
+        }
+    }
+
+    /// Reads a value of the given type from a slot of 8 bytes.
+    pub(crate) unsafe fn read(ty: ffi::M3ValueType::Type, slot: *mut u64) -> Option<Self> {
+        match ty {
+            ffi::M3ValueType::c_m3Type_i32 => Some(Value::I32(WasmType::pop_from_stack(slot))),
+            ffi::M3ValueType::c_m3Type_i64 => Some(Value::I64(WasmType::pop_from_stack(slot))),
+            ffi::M3ValueType::c_m3Type_f32 => Some(Value::F32(WasmType::pop_from_stack(slot))),
+            ffi::M3ValueType::c_m3Type_f64 => Some(Value::F64(WasmType::pop_from_stack(slot))),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! value_from_impl {
+    ($($ty:ty => $variant:ident as $repr:ty),*) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(value: $ty) -> Self {
+                    Value::$variant(value as $repr)
+                }
+            }
+        )*
+    };
+}
+value_from_impl!(
+    i32 => I32 as i32,
+    u32 => I32 as i32,
+    i64 => I64 as i64,
+    u64 => I64 as i64,
+    f32 => F32 as f32,
+    f64 => F64 as f64
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn test_value_slot() {
+        let mut slot = !0u64;
+        unsafe { Value::from(u32::MAX).write(&mut slot) };
+        assert_eq!(
+            unsafe { Value::read(ffi::M3ValueType::c_m3Type_i32, &mut slot) },
+            Some(Value::I32(-1))
+        );
+        unsafe { Value::F64(0.5).write(&mut slot) };
+        assert_eq!(
+            unsafe { Value::read(ffi::M3ValueType::c_m3Type_f64, &mut slot) },
+            Some(Value::F64(0.5))
+        );
+    }
+
     #[test]
     fn test_validate_types_single() {
         assert!(f64::validate_types(
@@ -25,6 +25,56 @@ pub fn str_to_cstr_owned(str: &str) -> Vec<cty::c_char> {
     cstr
 }
 
+/// Bound of data handed to a runtime, which is `Send` with the `sync` feature enabled so that
+/// the runtime can be moved to other threads, and implemented for all types otherwise.
+#[cfg(feature = "sync")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "sync")]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+/// Bound of data handed to a runtime, which is `Send` with the `sync` feature enabled so that
+/// the runtime can be moved to other threads, and implemented for all types otherwise.
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// Bound of data shared by runtimes, which is `Sync` with the `sync` feature enabled so that
+/// it can be shared between threads, and implemented for all types otherwise.
+#[cfg(feature = "sync")]
+pub trait MaybeSync: Sync {}
+#[cfg(feature = "sync")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+
+/// Bound of data shared by runtimes, which is `Sync` with the `sync` feature enabled so that
+/// it can be shared between threads, and implemented for all types otherwise.
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// The 64 bit FNV-1a hash, which unlike the hashers of std is guaranteed to stay the same
+/// across platforms and releases.
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl core::hash::Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,4 +107,18 @@ mod tests {
         let cstr = b"abcdef\0";
         assert_eq!(str_to_cstr_owned(str).as_slice(), &cstr.map(|c| c as i8));
     }
+
+    #[test]
+    fn test_fnv1a() {
+        use core::hash::Hasher;
+
+        let hash = |bytes: &[u8]| {
+            let mut hasher = Fnv1a::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
 }
@@ -0,0 +1,71 @@
+//! Host callbacks run whenever wasm3 yields during a call.
+//!
+//! wasm3 calls `m3_Yield` on every call of a wasm function, without telling which runtime it is
+//! executing. The `yield-hook` feature of wasm3-sys leaves that function to be defined here, and
+//! calls into runtimes record themselves as the current call of their thread so that it can
+//! find the runtime's callback.
+use core::cell::Cell;
+use core::ptr;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::error::{self, Trap};
+use crate::runtime::RuntimeState;
+
+/// What a call into a runtime does after its [yield callback] returned.
+///
+/// [yield callback]: crate::Runtime::set_yield_callback
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum YieldAction {
+    /// The call carries on.
+    Continue,
+    /// The call traps with [`Trap::Cancelled`].
+    Abort,
+}
+
+#[cfg(not(feature = "sync"))]
+pub(crate) type YieldCallback = dyn FnMut() -> YieldAction;
+#[cfg(feature = "sync")]
+pub(crate) type YieldCallback = dyn FnMut() -> YieldAction + Send;
+
+std::thread_local! {
+    // the runtime the innermost call on this thread is executing in
+    static CURRENT_RUNTIME: Cell<ffi::IM3Runtime> = const { Cell::new(ptr::null_mut()) };
+}
+
+/// Makes the given runtime the one yielding on this thread until the returned guard is dropped,
+/// which restores the runtime of the call this one is nested in.
+pub(crate) fn enter(runtime: ffi::IM3Runtime) -> CurrentRuntime {
+    CurrentRuntime(CURRENT_RUNTIME.with(|current| current.replace(runtime)))
+}
+
+pub(crate) struct CurrentRuntime(ffi::IM3Runtime);
+
+impl Drop for CurrentRuntime {
+    fn drop(&mut self) {
+        CURRENT_RUNTIME.with(|current| current.set(self.0));
+    }
+}
+
+#[no_mangle]
+extern "C" fn m3_Yield() -> ffi::M3Result {
+    let runtime = CURRENT_RUNTIME.with(Cell::get);
+    if runtime.is_null() {
+        return ptr::null();
+    }
+    // SAFETY: the runtime is executing a call on this thread, and the callback is taken out of
+    // its state while it runs so that no reference to the state is held meanwhile
+    let callback = unsafe { RuntimeState::from_raw(runtime) }
+        .yield_callback
+        .take();
+    let mut callback = match callback {
+        Some(callback) => callback,
+        None => return ptr::null(),
+    };
+    let action = panic::catch_unwind(AssertUnwindSafe(&mut callback));
+    unsafe { RuntimeState::from_raw(runtime) }.yield_callback = Some(callback);
+    match action {
+        Ok(YieldAction::Continue) => ptr::null(),
+        Ok(YieldAction::Abort) => Trap::Cancelled.as_ptr(),
+        Err(payload) => unsafe { error::stash_host_panic(runtime, payload) },
+    }
+}
@@ -1,6 +1,9 @@
+use wasm3::error::{Error, ErrorKind, Trap};
 use wasm3::Environment;
 use wasm3::Module;
+use wasm3::ParsedModule;
 use wasm3::Runtime;
+use wasm3::RuntimeBuilder;
 
 fn runtime() -> Runtime {
     Environment::new()
@@ -14,6 +17,31 @@ fn module(rt: &Runtime) -> Module {
         .expect("Unable to load module")
 }
 
+// (module (func (export "boom") unreachable))
+const BOOM: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x03, 0x02,
+    0x01, 0x00, 0x07, 0x08, 0x01, 0x04, 0x62, 0x6f, 0x6f, 0x6d, 0x00, 0x00, 0x0a, 0x05, 0x01, 0x03,
+    0x00, 0x00, 0x0b,
+];
+
+// (module
+//   (memory 1)
+//   (global $g (mut i32) (i32.const 0))
+//   (func (export "set") (param i32)
+//     (global.set $g (local.get 0))
+//     (i32.store (i32.const 0) (local.get 0)))
+//   (func (export "get") (result i32)
+//     (i32.add (global.get $g) (i32.load (i32.const 0))))
+//   (func (export "grow") (result i32) (memory.grow (i32.const 1))))
+const STATEFUL: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x09, 0x02, 0x60, 0x01, 0x7f, 0x00, 0x60,
+    0x00, 0x01, 0x7f, 0x03, 0x04, 0x03, 0x00, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x06, 0x06,
+    0x01, 0x7f, 0x01, 0x41, 0x00, 0x0b, 0x07, 0x14, 0x03, 0x03, 0x73, 0x65, 0x74, 0x00, 0x00, 0x03,
+    0x67, 0x65, 0x74, 0x00, 0x01, 0x04, 0x67, 0x72, 0x6f, 0x77, 0x00, 0x02, 0x0a, 0x21, 0x03, 0x0d,
+    0x00, 0x20, 0x00, 0x24, 0x00, 0x41, 0x00, 0x20, 0x00, 0x36, 0x02, 0x00, 0x0b, 0x0a, 0x00, 0x23,
+    0x00, 0x41, 0x00, 0x28, 0x02, 0x00, 0x6a, 0x0b, 0x06, 0x00, 0x41, 0x01, 0x40, 0x00, 0x0b,
+];
+
 #[test]
 fn test_add_u64() {
     let rt = runtime();
@@ -83,3 +111,1095 @@ fn test_no_args_no_ret_func() {
         .expect("Unable to find function");
     assert_eq!(func.call(), Ok(()));
 }
+
+#[test]
+fn test_unload_module() {
+    let mut rt = runtime();
+    let id = module(&rt).id();
+    assert_eq!(rt.unload_module(id), Ok(()));
+    assert_eq!(
+        rt.find_function::<(u64, u64), u64>("add_u64").err(),
+        Some(Error::FunctionNotFound)
+    );
+    assert_eq!(rt.unload_module(id), Err(Error::ModuleNotFound));
+
+    // modules sharing a name are told apart
+    let (first, second) = (module(&rt).id(), module(&rt).id());
+    assert_ne!(first, second);
+    assert_eq!(rt.unload_module(first), Ok(()));
+    let func = rt
+        .find_function::<(u64, u64), u64>("add_u64")
+        .expect("Unable to find function");
+    assert_eq!(func.module().map(|module| module.id()), Some(second));
+    assert_eq!(rt.unload_module(second), Ok(()));
+
+    let module = module(&rt);
+    let func = module
+        .find_function::<(u64, u64), u64>("add_u64")
+        .expect("Unable to find function");
+    assert_eq!(func.call(124, 612), Ok(736));
+}
+
+#[cfg(feature = "backtrace")]
+#[test]
+fn test_trap_backtrace() {
+    let rt = runtime();
+    let module = rt
+        .parse_and_load_module(BOOM)
+        .expect("Unable to load module");
+    let func = module
+        .find_function::<(), ()>("boom")
+        .expect("Unable to find function");
+    let err = func.call().unwrap_err();
+    let backtrace = err.backtrace().expect("no backtrace recorded");
+    let frame = &backtrace.frames()[0];
+    assert_eq!(frame.function_index(), Some(0));
+    assert_eq!(frame.function_name(), Some("boom"));
+}
+
+#[test]
+fn test_error_info_missing_import() {
+    let rt = runtime();
+    let module = module(&rt);
+    let err = module
+        .find_function::<(), ()>("call_imports")
+        .and_then(|func| func.call())
+        .unwrap_err();
+    let message = err.info().and_then(|info| info.message()).unwrap_or("");
+    assert!(message.contains("hello"), "unexpected message: {}", err);
+    assert!(matches!(err, Error::Wasm3(_)));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_host_error() {
+    use wasm3::error::{HostError, HostResult};
+
+    #[derive(Debug)]
+    struct Unavailable;
+    impl std::fmt::Display for Unavailable {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "database unavailable")
+        }
+    }
+    impl std::error::Error for Unavailable {}
+
+    let rt = runtime();
+    let mut module = module(&rt);
+    module
+        .link_fallible_closure("env", "hello", |_, ()| -> HostResult<()> {
+            Err(HostError::new(Unavailable))
+        })
+        .expect("Unable to link closure");
+    module
+        .link_closure("env", "mul_u32_and_f32", |_, (a, b): (u32, f32)| {
+            Ok(a as f64 * b as f64)
+        })
+        .expect("Unable to link closure");
+    let func = module
+        .find_function::<(), ()>("call_imports")
+        .expect("Unable to find function");
+    match func.call() {
+        Err(Error::Host(err)) => assert!(err.downcast_ref::<Unavailable>().is_some()),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_host_panic() {
+    let rt = runtime();
+    let mut module = module(&rt);
+    module
+        .link_closure("env", "hello", |_, ()| -> Result<(), _> {
+            panic!("host function failed")
+        })
+        .expect("Unable to link closure");
+    module
+        .link_closure("env", "mul_u32_and_f32", |_, (a, b): (u32, f32)| {
+            Ok(a as f64 * b as f64)
+        })
+        .expect("Unable to link closure");
+    let func = module
+        .find_function::<(), ()>("call_imports")
+        .expect("Unable to find function");
+    match func.call() {
+        Err(Error::HostPanic(payload)) => {
+            assert_eq!(
+                payload.downcast_ref::<&str>(),
+                Some(&"host function failed")
+            )
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+    // the runtime is still usable after the panic has been caught
+    let add = module
+        .find_function::<(u64, u64), u64>("add_u64")
+        .expect("Unable to find function");
+    assert_eq!(add.call(124, 612), Ok(736));
+}
+
+#[cfg(feature = "wasi")]
+#[test]
+fn test_wasi_exit_code() {
+    // (module
+    //   (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+    //   (memory (export "memory") 1)
+    //   (func (export "_start") i32.const 3 call 0))
+    const EXIT_3: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x60, 0x01, 0x7f, 0x00,
+        0x60, 0x00, 0x00, 0x02, 0x24, 0x01, 0x16, 0x77, 0x61, 0x73, 0x69, 0x5f, 0x73, 0x6e, 0x61,
+        0x70, 0x73, 0x68, 0x6f, 0x74, 0x5f, 0x70, 0x72, 0x65, 0x76, 0x69, 0x65, 0x77, 0x31, 0x09,
+        0x70, 0x72, 0x6f, 0x63, 0x5f, 0x65, 0x78, 0x69, 0x74, 0x00, 0x00, 0x03, 0x02, 0x01, 0x01,
+        0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x13, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79,
+        0x02, 0x00, 0x06, 0x5f, 0x73, 0x74, 0x61, 0x72, 0x74, 0x00, 0x01, 0x0a, 0x08, 0x01, 0x06,
+        0x00, 0x41, 0x03, 0x10, 0x00, 0x0b,
+    ];
+    let rt = runtime();
+    let mut program = rt
+        .parse_and_load_module(EXIT_3)
+        .expect("Unable to load module");
+    program.link_wasi().expect("Unable to link wasi");
+    let func = program
+        .find_function::<(), ()>("_start")
+        .expect("Unable to find function");
+    assert_eq!(func.call(), Err(Error::Exit(3)));
+
+    // host functions exiting don't report the exit code of another call
+    let rt = runtime();
+    let mut imports = module(&rt);
+    imports
+        .link_closure("env", "hello", |_, ()| Err::<(), _>(Trap::Exit))
+        .expect("Unable to link closure");
+    imports
+        .link_closure("env", "mul_u32_and_f32", |_, (a, b): (u32, f32)| {
+            Ok(a as f64 * b as f64)
+        })
+        .expect("Unable to link closure");
+    let func = imports
+        .find_function::<(), ()>("call_imports")
+        .expect("Unable to find function");
+    match func.call() {
+        Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::Exit)),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_error_kind() {
+    let env = Environment::new().expect("Unable to create environment");
+    let err = Module::parse(&env, &b"\0asm\x02\0\0\0"[..]).err();
+    assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::Parse));
+
+    let rt = runtime();
+    let err = module(&rt).find_function::<(), ()>("missing").err();
+    assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::Link));
+
+    let module = rt
+        .parse_and_load_module(BOOM)
+        .expect("Unable to load module");
+    let func = module
+        .find_function::<(), ()>("boom")
+        .expect("Unable to find function");
+    let err = func.call().err();
+    assert_eq!(
+        err.map(|err| err.kind()),
+        Some(ErrorKind::Trap(Trap::Unreachable))
+    );
+}
+
+#[test]
+fn test_recovery_after_trap() {
+    // (module
+    //   (import "env" "fail" (func))
+    //   (memory 1)
+    //   (func (export "recurse") call 1)
+    //   (func (export "oob") (result i32) i32.const 65536 i32.load)
+    //   (func (export "add") (param i32 i32) (result i32) local.get 0 local.get 1 i32.add)
+    //   (func (export "host") call 0))
+    const TRAPS: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0e, 0x03, 0x60, 0x00, 0x00, 0x60,
+        0x00, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x02, 0x0c, 0x01, 0x03, 0x65, 0x6e,
+        0x76, 0x04, 0x66, 0x61, 0x69, 0x6c, 0x00, 0x00, 0x03, 0x05, 0x04, 0x00, 0x01, 0x02, 0x00,
+        0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x1e, 0x04, 0x07, 0x72, 0x65, 0x63, 0x75, 0x72, 0x73,
+        0x65, 0x00, 0x01, 0x03, 0x6f, 0x6f, 0x62, 0x00, 0x02, 0x03, 0x61, 0x64, 0x64, 0x00, 0x03,
+        0x04, 0x68, 0x6f, 0x73, 0x74, 0x00, 0x04, 0x0a, 0x1d, 0x04, 0x04, 0x00, 0x10, 0x01, 0x0b,
+        0x09, 0x00, 0x41, 0x80, 0x80, 0x04, 0x28, 0x02, 0x00, 0x0b, 0x07, 0x00, 0x20, 0x00, 0x20,
+        0x01, 0x6a, 0x0b, 0x04, 0x00, 0x10, 0x00, 0x0b,
+    ];
+    let rt = runtime();
+    let mut module = rt
+        .parse_and_load_module(TRAPS)
+        .expect("Unable to load module");
+    module
+        .link_closure("env", "fail", |_, ()| -> Result<(), _> { Err(Trap::Abort) })
+        .expect("Unable to link closure");
+    let recurse = module
+        .find_function::<(), ()>("recurse")
+        .expect("Unable to find function");
+    let oob = module
+        .find_function::<(), u32>("oob")
+        .expect("Unable to find function");
+    let host = module
+        .find_function::<(), ()>("host")
+        .expect("Unable to find function");
+    let add = module
+        .find_function::<(u32, u32), u32>("add")
+        .expect("Unable to find function");
+
+    for _ in 0..100 {
+        match recurse.call() {
+            Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::StackOverflow)),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(add.call(1, 2), Ok(3));
+        match oob.call() {
+            Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::OutOfBoundsMemoryAccess)),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(add.call(3, 4), Ok(7));
+        match host.call() {
+            Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::Abort)),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        rt.reset_error_state();
+        assert_eq!(add.call(5, 6), Ok(11));
+    }
+}
+
+#[test]
+fn test_fuel_metering() {
+    // (module
+    //   (func (export "spin") (loop (br 0)))
+    //   (func (export "count") (param i32) (result i32) (local i32)
+    //     (loop
+    //       (local.set 1 (i32.add (local.get 1) (i32.const 1)))
+    //       (br_if 0 (i32.lt_u (local.get 1) (local.get 0))))
+    //     (local.get 1)))
+    const SPIN: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x09, 0x02, 0x60, 0x00, 0x00, 0x60,
+        0x01, 0x7f, 0x01, 0x7f, 0x03, 0x03, 0x02, 0x00, 0x01, 0x07, 0x10, 0x02, 0x04, 0x73, 0x70,
+        0x69, 0x6e, 0x00, 0x00, 0x05, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x00, 0x01, 0x0a, 0x21, 0x02,
+        0x07, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b, 0x17, 0x01, 0x01, 0x7f, 0x03, 0x40, 0x20,
+        0x01, 0x41, 0x01, 0x6a, 0x21, 0x01, 0x20, 0x01, 0x20, 0x00, 0x49, 0x0d, 0x00, 0x0b, 0x20,
+        0x01, 0x0b,
+    ];
+    let env = Environment::new().expect("Unable to create environment");
+    let rt = env
+        .create_runtime(1024 * 60)
+        .expect("Unable to create runtime");
+    let module = ParsedModule::parse_metered(&env, SPIN).expect("Unable to parse module");
+    let module = rt.load_module(module).expect("Unable to load module");
+    let spin = module
+        .find_function::<(), ()>("spin")
+        .expect("Unable to find function");
+    let count = module
+        .find_function::<u32, u32>("count")
+        .expect("Unable to find function");
+
+    assert_eq!(rt.fuel_remaining(), None);
+    assert_eq!(count.call(10), Ok(10));
+
+    rt.set_fuel(1000);
+    assert_eq!(count.call(10), Ok(10));
+    let remaining = rt.fuel_remaining().unwrap();
+    assert!(remaining > 0 && remaining < 1000);
+
+    match spin.call() {
+        Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::OutOfFuel)),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(rt.fuel_remaining(), Some(0));
+
+    rt.set_fuel(1000);
+    assert_eq!(count.call(10), Ok(10));
+
+    #[cfg(feature = "std")]
+    {
+        let timeout = std::time::Duration::from_millis(10);
+        rt.set_fuel(u64::MAX);
+        match rt.call_with_timeout(timeout, || spin.call()) {
+            Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::Interrupted)),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(rt.call_with_timeout(timeout, || count.call(10)), Ok(10));
+        assert_eq!(count.call(10), Ok(10));
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_call_with_timeout() {
+    // (module
+    //   (func (export "spin") (loop (br 0)))
+    //   (func (export "one") (result i32) i32.const 1))
+    const SPIN: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x60, 0x00, 0x00, 0x60,
+        0x00, 0x01, 0x7f, 0x03, 0x03, 0x02, 0x00, 0x01, 0x07, 0x0e, 0x02, 0x04, 0x73, 0x70, 0x69,
+        0x6e, 0x00, 0x00, 0x03, 0x6f, 0x6e, 0x65, 0x00, 0x01, 0x0a, 0x0e, 0x02, 0x07, 0x00, 0x03,
+        0x40, 0x0c, 0x00, 0x0b, 0x0b, 0x04, 0x00, 0x41, 0x01, 0x0b,
+    ];
+    let env = Environment::new().expect("Unable to create environment");
+    let rt = env
+        .create_runtime(1024 * 60)
+        .expect("Unable to create runtime");
+    // not metered, interrupts are checked nonetheless
+    let parsed = ParsedModule::parse_interruptible(&env, SPIN).expect("Unable to parse module");
+    assert!(parsed.is_interruptible());
+    let module = rt.load_module(parsed).expect("Unable to load module");
+    let spin = module
+        .find_function::<(), ()>("spin")
+        .expect("Unable to find function");
+    let one = module
+        .find_function::<(), i32>("one")
+        .expect("Unable to find function");
+    let timeout = std::time::Duration::from_millis(10);
+    for _ in 0..2 {
+        match rt.call_with_timeout(timeout, || spin.call()) {
+            Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::Interrupted)),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(one.call(), Ok(1));
+    }
+
+    // an interrupt arriving between calls interrupts the next call
+    rt.interrupt_handle().interrupt();
+    match spin.call() {
+        Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::Interrupted)),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    rt.interrupt_handle().interrupt();
+    match rt.call_with_timeout(std::time::Duration::from_secs(60), || one.call()) {
+        Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::Interrupted)),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(rt.call_with_timeout(timeout, || one.call()), Ok(1));
+
+    // modules aren't instrumented unless asked to, and a trap is reported as it is
+    let rt = env
+        .create_runtime(1024 * 60)
+        .expect("Unable to create runtime");
+    let parsed = ParsedModule::parse(&env, BOOM).expect("Unable to parse module");
+    assert!(!parsed.is_interruptible());
+    let module = rt.load_module(parsed).expect("Unable to load module");
+    let boom = module
+        .find_function::<(), ()>("boom")
+        .expect("Unable to find function");
+    rt.interrupt_handle().interrupt();
+    match boom.call() {
+        Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::Unreachable)),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn test_environment_reentrance() {
+    use std::sync::{Arc, Mutex};
+
+    // (module
+    //   (import "env" "host" (func $host))
+    //   (func $init call $host)
+    //   (func (export "f") call $host)
+    //   (start $init))
+    const START: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x02,
+        0x0c, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x04, 0x68, 0x6f, 0x73, 0x74, 0x00, 0x00, 0x03, 0x03,
+        0x02, 0x00, 0x00, 0x07, 0x05, 0x01, 0x01, 0x66, 0x00, 0x02, 0x08, 0x01, 0x01, 0x0a, 0x0b,
+        0x02, 0x04, 0x00, 0x10, 0x00, 0x0b, 0x04, 0x00, 0x10, 0x00, 0x0b,
+    ];
+    let env = Environment::new().expect("Unable to create environment");
+    let rt = env
+        .create_runtime(1024 * 60)
+        .expect("Unable to create runtime");
+    let mut module = rt
+        .parse_and_load_module(START)
+        .expect("Unable to load module");
+    let parsed = Arc::new(Mutex::new(Vec::new()));
+    let (host_env, host_parsed) = (env.clone(), parsed.clone());
+    module
+        .link_closure("env", "host", move |_, ()| {
+            let result = ParsedModule::parse(&host_env, BOOM).map(drop);
+            host_parsed.lock().unwrap().push(result);
+            // dropping a runtime doesn't deadlock either
+            drop(
+                host_env
+                    .create_runtime(1024)
+                    .expect("Unable to create runtime"),
+            );
+            Ok(())
+        })
+        .expect("Unable to link closure");
+
+    // the start function runs while the lookup holds the environment's lock, so parsing fails
+    // instead of deadlocking, while calls don't hold the lock
+    let f = module
+        .find_function::<(), ()>("f")
+        .expect("Unable to find function");
+    assert_eq!(f.call(), Ok(()));
+    assert_eq!(
+        *parsed.lock().unwrap(),
+        [Err(Error::EnvironmentLocked), Ok(())]
+    );
+}
+
+#[test]
+fn test_runtime_builder() {
+    let env = Environment::new().expect("Unable to create environment");
+    let err = RuntimeBuilder::new(&env).stack_size_bytes(0).build().err();
+    assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::Runtime));
+    let err = RuntimeBuilder::new(&env)
+        .stack_size_bytes(1001)
+        .build()
+        .err();
+    assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::Runtime));
+    let err = RuntimeBuilder::new(&env)
+        .memory_limit_pages(0)
+        .build()
+        .err();
+    assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::Runtime));
+
+    let rt = RuntimeBuilder::new(&env)
+        .stack_size_slots(16 * 1024)
+        .memory_limit_pages(16)
+        .table_limit(4)
+        .user_data(7u32)
+        .compile_eagerly(true)
+        .build()
+        .expect("Unable to create runtime");
+    assert_eq!(rt.user_data::<u32>(), Some(&7));
+    assert_eq!(rt.user_data::<u64>(), None);
+
+    // (module (memory 17))
+    const MEMORY: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x11,
+    ];
+    // (module (table 10 funcref))
+    const TABLE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x04, 0x04, 0x01, 0x70, 0x00, 0x0a,
+    ];
+    assert_eq!(
+        rt.parse_and_load_module(MEMORY).err(),
+        Some(Error::MemoryLimitExceeded)
+    );
+    assert_eq!(
+        rt.parse_and_load_module(TABLE).err(),
+        Some(Error::TableLimitExceeded)
+    );
+
+    // the module is compiled once its imports are linked, on the first lookup
+    let mut module = module(&rt);
+    let err = module.find_function::<(u32, u32), u32>("add_u32").err();
+    assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::Link));
+    module
+        .link_closure("env", "hello", |ctx, ()| {
+            assert_eq!(ctx.user_data::<u32>(), Some(&7));
+            Ok(())
+        })
+        .expect("Unable to link closure");
+    module
+        .link_closure("env", "mul_u32_and_f32", |_, (a, b): (u32, f32)| {
+            Ok(a as f64 * b as f64)
+        })
+        .expect("Unable to link closure");
+    let func = module
+        .find_function::<(), ()>("call_imports")
+        .expect("Unable to find function");
+    assert_eq!(func.call(), Ok(()));
+    let func = module
+        .find_function::<(u32, u32), u32>("add_u32")
+        .expect("Unable to find function");
+    assert_eq!(func.call(1, 2), Ok(3));
+}
+
+#[test]
+fn test_call_stats() {
+    // (module (memory 1) (func (export "grow") (result i32) (memory.grow (i32.const 2))))
+    const GROW: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f,
+        0x03, 0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x08, 0x01, 0x04, 0x67, 0x72,
+        0x6f, 0x77, 0x00, 0x00, 0x0a, 0x08, 0x01, 0x06, 0x00, 0x41, 0x02, 0x40, 0x00, 0x0b,
+    ];
+    let env = Environment::new().expect("Unable to create environment");
+    let rt = RuntimeBuilder::new(&env)
+        .record_call_stats(true)
+        .build()
+        .expect("Unable to create runtime");
+    assert_eq!(rt.last_call_stats(), None);
+
+    let mut module = module(&rt);
+    module
+        .link_closure("env", "hello", |_, ()| Ok(()))
+        .expect("Unable to link closure");
+    module
+        .link_closure("env", "mul_u32_and_f32", |_, (a, b): (u32, f32)| {
+            Ok(a as f64 * b as f64)
+        })
+        .expect("Unable to link closure");
+    let func = module
+        .find_function::<(), ()>("call_imports")
+        .expect("Unable to find function");
+    assert_eq!(func.call(), Ok(()));
+    let stats = rt.last_call_stats().unwrap();
+    assert_eq!(stats.host_calls(), 2);
+    assert_eq!(stats.memory_growth(), 0);
+    assert!(stats.peak_stack_bytes() > 0);
+
+    let rt = RuntimeBuilder::new(&env)
+        .record_call_stats(true)
+        .build()
+        .expect("Unable to create runtime");
+    let module = rt
+        .parse_and_load_module(GROW)
+        .expect("Unable to load module");
+    let func = module
+        .find_function::<(), u32>("grow")
+        .expect("Unable to find function");
+    assert_eq!(func.call(), Ok(1));
+    let stats = rt.last_call_stats().unwrap();
+    assert_eq!(stats.host_calls(), 0);
+    assert_eq!(stats.memory_growth(), 2 * 64 * 1024);
+}
+
+#[test]
+fn test_deterministic() {
+    // (module
+    //   (func (export "nan32") (result i32)
+    //     (i32.reinterpret_f32 (f32.add (f32.const nan:0x1234) (f32.const 1))))
+    //   (func (export "nan64") (result i64)
+    //     (i64.reinterpret_f64 (f64.sqrt (f64.const -nan:0x1234)))))
+    const NANS: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x09, 0x02, 0x60, 0x00, 0x01, 0x7f,
+        0x60, 0x00, 0x01, 0x7e, 0x03, 0x03, 0x02, 0x00, 0x01, 0x07, 0x11, 0x02, 0x05, 0x6e, 0x61,
+        0x6e, 0x33, 0x32, 0x00, 0x00, 0x05, 0x6e, 0x61, 0x6e, 0x36, 0x34, 0x00, 0x01, 0x0a, 0x1e,
+        0x02, 0x0e, 0x00, 0x43, 0x34, 0x12, 0x80, 0x7f, 0x43, 0x00, 0x00, 0x80, 0x3f, 0x92, 0xbc,
+        0x0b, 0x0d, 0x00, 0x44, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0xf0, 0xff, 0x9f, 0xbd, 0x0b,
+    ];
+    // (module (memory 1 1 shared))
+    const SHARED: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x03, 0x01, 0x01,
+    ];
+    let env = Environment::new().expect("Unable to create environment");
+    let rt = RuntimeBuilder::new(&env)
+        .deterministic(true)
+        .build()
+        .expect("Unable to create runtime");
+    assert!(rt.is_deterministic());
+
+    let module = rt
+        .parse_and_load_module(NANS)
+        .expect("Unable to load module");
+    let nan32 = module
+        .find_function::<(), u32>("nan32")
+        .expect("Unable to find function");
+    assert_eq!(nan32.call(), Ok(0x7fc0_0000));
+    let nan64 = module
+        .find_function::<(), u64>("nan64")
+        .expect("Unable to find function");
+    assert_eq!(nan64.call(), Ok(0x7ff8_0000_0000_0000));
+
+    let err = rt.parse_and_load_module(SHARED).err();
+    assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::Parse));
+
+    // shared modules can be instrumented once for all runtimes, deterministic or not
+    let shared = wasm3::SharedModule::parse(&env, NANS)
+        .and_then(wasm3::SharedModule::into_deterministic)
+        .expect("Unable to instrument module");
+    let plain = env
+        .create_runtime(1024 * 60)
+        .expect("Unable to create runtime");
+    for rt in [&rt, &plain] {
+        rt.load_shared_module(&shared)
+            .expect("Unable to load module");
+        let nan32 = rt
+            .find_function::<(), u32>("nan32")
+            .expect("Unable to find function");
+        assert_eq!(nan32.call(), Ok(0x7fc0_0000));
+    }
+    let err = wasm3::SharedModule::parse(&env, SHARED)
+        .and_then(wasm3::SharedModule::into_deterministic)
+        .err();
+    assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::Parse));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_quota() {
+    use wasm3::error::QuotaExceeded;
+    use wasm3::Quota;
+
+    let rt = runtime();
+    let mut module = module(&rt);
+    let quota = Quota::new(3);
+    module
+        .link_closure_with_quota("env", "hello", &quota, 1, |_, ()| Ok(()))
+        .expect("Unable to link closure");
+    module
+        .link_closure_with_quota(
+            "env",
+            "mul_u32_and_f32",
+            &quota,
+            2,
+            |_, (a, b): (u32, f32)| Ok(a as f64 * b as f64),
+        )
+        .expect("Unable to link closure");
+    let func = module
+        .find_function::<(), ()>("call_imports")
+        .expect("Unable to find function");
+    assert_eq!(func.call(), Ok(()));
+    assert_eq!(quota.remaining(), 0);
+    match func.call() {
+        Err(Error::Host(err)) => {
+            let err = err.downcast_ref::<QuotaExceeded>().unwrap();
+            assert_eq!((err.module(), err.function()), ("env", "hello"));
+            assert_eq!((err.cost(), err.remaining()), (1, 0));
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    quota.reset();
+    assert_eq!(quota.remaining(), 3);
+    assert_eq!(func.call(), Ok(()));
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn test_send_runtime() {
+    let env = Environment::new().expect("Unable to create environment");
+    let rt = env
+        .create_runtime(1024 * 60)
+        .expect("Unable to create runtime");
+    let parsed = env
+        .parse_module(&include_bytes!("wasm_test_bins/wasm_test_bins.wasm")[..])
+        .expect("Unable to parse module");
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+    let counter = calls.clone();
+
+    let worker = std::thread::spawn(move || {
+        let mut module = rt.load_module(parsed).expect("Unable to load module");
+        module
+            .link_closure("env", "hello", move |_, ()| {
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Ok(())
+            })
+            .expect("Unable to link closure");
+        module
+            .link_closure("env", "mul_u32_and_f32", |_, (a, b): (u32, f32)| {
+                Ok(a as f64 * b as f64)
+            })
+            .expect("Unable to link closure");
+        let func = module
+            .find_function::<(), ()>("call_imports")
+            .expect("Unable to find function");
+        assert_eq!(func.call(), Ok(()));
+        drop(module);
+        rt
+    });
+    let rt = worker.join().unwrap();
+    assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+    let func = rt
+        .find_function::<(u32, u32), u32>("add_u32")
+        .expect("Unable to find function");
+    assert_eq!(func.call(1, 2), Ok(3));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_runtime_pool() {
+    use wasm3::{RuntimePool, SharedModule};
+
+    let env = Environment::new().expect("Unable to create environment");
+    let create_env = env.clone();
+    let module = SharedModule::parse(
+        &env,
+        &include_bytes!("wasm_test_bins/wasm_test_bins.wasm")[..],
+    )
+    .expect("Unable to parse module");
+    let pool = RuntimePool::new(
+        module,
+        2,
+        move || create_env.create_runtime(1024 * 60),
+        |module| {
+            module.link_closure("env", "hello", |_, ()| Ok(()))?;
+            module.link_closure("env", "mul_u32_and_f32", |_, (a, b): (u32, f32)| {
+                Ok(a as f64 * b as f64)
+            })
+        },
+    )
+    .expect("Unable to create pool");
+    assert_eq!(pool.idle_count(), 2);
+    {
+        let rt = pool.get().expect("Unable to get runtime");
+        assert_eq!(pool.idle_count(), 1);
+        let func = rt
+            .find_function::<(), ()>("call_imports")
+            .expect("Unable to find function");
+        assert_eq!(func.call(), Ok(()));
+    }
+    assert_eq!(pool.idle_count(), 2);
+
+    // runtimes created beyond the pool's size are dropped on return
+    let runtimes = (0..3)
+        .map(|_| pool.get().expect("Unable to get runtime"))
+        .collect::<Vec<_>>();
+    assert_eq!(pool.idle_count(), 0);
+    drop(runtimes);
+    assert_eq!(pool.idle_count(), 2);
+    pool.get().expect("Unable to get runtime").discard();
+    assert_eq!(pool.idle_count(), 1);
+
+    // runtimes in which a call failed are not returned
+    let module = SharedModule::parse(&env, BOOM).expect("Unable to parse module");
+    let pool = RuntimePool::new(module, 1, move || env.create_runtime(1024 * 60), |_| Ok(()))
+        .expect("Unable to create pool");
+    {
+        let rt = pool.get().expect("Unable to get runtime");
+        let func = rt
+            .find_function::<(), ()>("boom")
+            .expect("Unable to find function");
+        assert!(func.call().is_err());
+    }
+    assert_eq!(pool.idle_count(), 0);
+}
+
+#[test]
+fn test_call_dynamic() {
+    use wasm3::Value;
+
+    let rt = runtime();
+    let _module = module(&rt);
+    assert_eq!(
+        rt.call("add_u64", &[Value::I64(124), Value::from(612u64)]),
+        Ok(vec![Value::I64(736)])
+    );
+    assert_eq!(rt.call("no_return", &[Value::I64(0)]), Ok(vec![]));
+    assert_eq!(
+        rt.call("add_u64", &[Value::I32(124), Value::I32(612)]),
+        Err(Error::InvalidFunctionSignature)
+    );
+    assert_eq!(
+        rt.call("add_u64", &[]),
+        Err(Error::InvalidFunctionSignature)
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_async_runtime() {
+    use wasm3::{block_on, AsyncRuntime, Value};
+
+    let runtime = AsyncRuntime::new(|| {
+        let rt = runtime();
+        {
+            let mut module = module(&rt);
+            // host functions may wait on async work
+            module.link_closure("env", "hello", |_, ()| {
+                block_on(async {});
+                Ok(())
+            })?;
+            module.link_closure("env", "mul_u32_and_f32", |_, (a, b): (u32, f32)| {
+                Ok(block_on(async move { a as f64 * b as f64 }))
+            })?;
+        }
+        Ok(rt)
+    })
+    .expect("Unable to create runtime");
+
+    let add = runtime.call("add_u32", &[Value::I32(1), Value::I32(2)]);
+    let imports = runtime.run(|rt| {
+        rt.find_function::<(), ()>("call_imports")
+            .and_then(|func| func.call())
+    });
+    assert_eq!(block_on(add), Ok(vec![Value::I32(3)]));
+    assert_eq!(block_on(imports), Ok(()));
+    assert_eq!(
+        block_on(runtime.call("missing", &[])),
+        Err(Error::FunctionNotFound)
+    );
+
+    // a panicking job resumes its panic in the future and leaves the runtime usable
+    let panicking = runtime.run(|_| -> () { panic!("job failed") });
+    let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| block_on(panicking)))
+        .expect_err("the job's panic is resumed");
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"));
+    assert_eq!(
+        block_on(runtime.call("add_u32", &[Value::I32(1), Value::I32(2)])),
+        Ok(vec![Value::I32(3)])
+    );
+
+    let err = AsyncRuntime::new(|| Err(Error::FunctionNotFound)).err();
+    assert_eq!(err, Some(Error::FunctionNotFound));
+}
+
+#[cfg(feature = "yield-callback")]
+#[test]
+fn test_yield_callback() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use wasm3::YieldAction;
+
+    // (module
+    //   (func $step)
+    //   (func (export "spin") (loop (call $step) (br 0))))
+    const SPIN: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x03,
+        0x03, 0x02, 0x00, 0x00, 0x07, 0x08, 0x01, 0x04, 0x73, 0x70, 0x69, 0x6e, 0x00, 0x01, 0x0a,
+        0x0e, 0x02, 0x02, 0x00, 0x0b, 0x09, 0x00, 0x03, 0x40, 0x10, 0x00, 0x0c, 0x00, 0x0b, 0x0b,
+    ];
+    let rt = runtime();
+    let spin_module = rt
+        .parse_and_load_module(SPIN)
+        .expect("Unable to load module");
+    let spin = spin_module
+        .find_function::<(), ()>("spin")
+        .expect("Unable to find function");
+
+    let progress = Arc::new(AtomicU32::new(0));
+    let counter = progress.clone();
+    rt.set_yield_callback(move || {
+        if counter.fetch_add(1, Ordering::Relaxed) + 1 < 100 {
+            YieldAction::Continue
+        } else {
+            YieldAction::Abort
+        }
+    });
+    match spin.call() {
+        Err(Error::Wasm3(err)) => assert!(err.is_trap(Trap::Cancelled)),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(progress.load(Ordering::Relaxed), 100);
+
+    rt.set_yield_callback(|| panic!("cancelled"));
+    match spin.call() {
+        Err(Error::HostPanic(payload)) => {
+            assert_eq!(payload.downcast_ref::<&str>(), Some(&"cancelled"))
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // calls don't yield to other runtimes' callbacks
+    rt.clear_yield_callback();
+    let other = runtime();
+    other.set_yield_callback(|| YieldAction::Abort);
+    let add = module(&rt)
+        .find_function::<(u64, u64), u64>("add_u64")
+        .expect("Unable to find function");
+    assert_eq!(add.call(124, 612), Ok(736));
+}
+
+#[test]
+fn test_snapshot_restore() {
+    let rt = runtime();
+    let stateful = rt
+        .parse_and_load_module(STATEFUL)
+        .expect("Unable to load module");
+    let set = stateful
+        .find_function::<u32, ()>("set")
+        .expect("Unable to find function");
+    let get = stateful
+        .find_function::<(), u32>("get")
+        .expect("Unable to find function");
+    let grow = stateful
+        .find_function::<(), u32>("grow")
+        .expect("Unable to find function");
+
+    assert_eq!(set.call(21), Ok(()));
+    let initialized = rt.snapshot();
+    assert_eq!(initialized.memory_pages(), 1);
+    assert_eq!(&initialized.memory()[..4], &21u32.to_le_bytes());
+
+    assert_eq!(set.call(50), Ok(()));
+    assert_eq!(grow.call(), Ok(1));
+    assert_eq!(get.call(), Ok(100));
+    assert_eq!(rt.restore(&initialized), Ok(()));
+    assert_eq!(get.call(), Ok(42));
+    assert_eq!(rt.snapshot().memory_pages(), 1);
+
+    // snapshots can be restored into other runtimes with the same modules loaded
+    let other = runtime();
+    let other_module = other
+        .parse_and_load_module(STATEFUL)
+        .expect("Unable to load module");
+    assert_eq!(other.restore(&initialized), Ok(()));
+    let get = other_module
+        .find_function::<(), u32>("get")
+        .expect("Unable to find function");
+    assert_eq!(get.call(), Ok(42));
+
+    other
+        .parse_and_load_module(BOOM)
+        .expect("Unable to load module");
+    assert_eq!(other.restore(&initialized), Err(Error::SnapshotMismatch));
+    assert_eq!(get.call(), Ok(42));
+}
+
+#[test]
+fn test_snapshot_serialized() {
+    use wasm3::Snapshot;
+
+    let rt = runtime();
+    rt.parse_and_load_module(STATEFUL)
+        .and_then(|module| module.find_function::<u32, ()>("set"))
+        .and_then(|set| set.call(21))
+        .expect("Unable to initialize module");
+    let bytes = rt.snapshot().to_bytes();
+
+    // e.g. in another process
+    let snapshot = Snapshot::from_bytes(&bytes).expect("Unable to read snapshot");
+    let other = runtime();
+    let get = other
+        .parse_and_load_module(STATEFUL)
+        .and_then(|module| module.find_function::<(), u32>("get"))
+        .expect("Unable to find function");
+    assert_eq!(other.restore(&snapshot), Ok(()));
+    assert_eq!(get.call(), Ok(42));
+
+    // modules are identified by their contents
+    let boom = runtime();
+    boom.parse_and_load_module(BOOM)
+        .expect("Unable to load module");
+    assert_eq!(boom.restore(&snapshot), Err(Error::SnapshotMismatch));
+
+    assert_eq!(
+        Snapshot::from_bytes(&bytes[..bytes.len() - 1]).map(|_| ()),
+        Err(Error::InvalidSnapshot("checksum mismatch"))
+    );
+}
+
+#[test]
+fn test_reset_dirty_pages() {
+    let rt = runtime();
+    let stateful = rt
+        .parse_and_load_module(STATEFUL)
+        .expect("Unable to load module");
+    let set = stateful
+        .find_function::<u32, ()>("set")
+        .expect("Unable to find function");
+    let get = stateful
+        .find_function::<(), u32>("get")
+        .expect("Unable to find function");
+    let grow = stateful
+        .find_function::<(), u32>("grow")
+        .expect("Unable to find function");
+
+    assert_eq!(set.call(21), Ok(()));
+    let baseline = rt.snapshot();
+    assert_eq!(rt.dirty_pages(&baseline), Ok(vec![]));
+
+    assert_eq!(set.call(50), Ok(()));
+    assert_eq!(rt.dirty_pages(&baseline), Ok(vec![0]));
+    assert_eq!(grow.call(), Ok(1));
+    assert_eq!(rt.dirty_pages(&baseline), Ok(vec![0, 1]));
+
+    // grown pages are dropped rather than copied
+    assert_eq!(rt.reset_dirty_pages(&baseline), Ok(1));
+    assert_eq!(get.call(), Ok(42));
+    assert_eq!(rt.dirty_pages(&baseline), Ok(vec![]));
+    assert_eq!(rt.reset_dirty_pages(&baseline), Ok(0));
+}
+
+#[test]
+fn test_shared_module() {
+    use wasm3::SharedModule;
+
+    let env = Environment::new().expect("Unable to create environment");
+    let shared = SharedModule::parse(&env, STATEFUL).expect("Unable to parse module");
+    let runtimes = (0..2)
+        .map(|_| env.create_runtime(1024 * 60))
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Unable to create runtime");
+    let modules = runtimes
+        .iter()
+        .map(|rt| rt.load_shared_module(&shared))
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Unable to load module");
+
+    // each runtime has an instance of its own
+    for (module, value) in modules.iter().zip([1, 2]) {
+        module
+            .find_function::<u32, ()>("set")
+            .and_then(|set| set.call(value))
+            .expect("Unable to call function");
+    }
+    for (module, value) in modules.iter().zip([2, 4]) {
+        let get = module
+            .find_function::<(), u32>("get")
+            .expect("Unable to find function");
+        assert_eq!(get.call(), Ok(value));
+    }
+
+    let other = runtime();
+    assert!(matches!(
+        other.load_shared_module(&shared),
+        Err(Error::ModuleLoadEnvMismatch)
+    ));
+}
+
+#[test]
+fn test_parse_borrowed_data() {
+    use std::sync::Arc;
+
+    let env = Environment::new().expect("Unable to create environment");
+    let modules = [
+        ParsedModule::parse_static(&env, STATEFUL),
+        ParsedModule::parse_shared(&env, Arc::from(STATEFUL)),
+        ParsedModule::parse_source(&env, STATEFUL.to_vec()),
+    ];
+    for module in modules {
+        let rt = env
+            .create_runtime(1024 * 60)
+            .expect("Unable to create runtime");
+        let module = rt
+            .load_module(module.expect("Unable to parse module"))
+            .expect("Unable to load module");
+        module
+            .find_function::<u32, ()>("set")
+            .and_then(|set| set.call(21))
+            .expect("Unable to call function");
+        let get = module
+            .find_function::<(), u32>("get")
+            .expect("Unable to find function");
+        assert_eq!(get.call(), Ok(42));
+    }
+
+    assert!(matches!(
+        ParsedModule::parse_static(&env, &STATEFUL[..20]),
+        Err(Error::Parse(_))
+    ));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_parse_module_file() {
+    use std::path::Path;
+
+    let env = Environment::new().expect("Unable to create environment");
+    let path =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/wasm_test_bins/wasm_test_bins.wasm");
+    let rt = env
+        .create_runtime(1024 * 60)
+        .expect("Unable to create runtime");
+    let module = env
+        .parse_module_file(&path)
+        .and_then(|module| rt.load_module(module))
+        .expect("Unable to load module");
+    let add = module
+        .find_function::<(u64, u64), u64>("add_u64")
+        .expect("Unable to find function");
+    assert_eq!(add.call(124, 612), Ok(736));
+
+    let missing = path.with_extension("missing");
+    match env.parse_module_file(&missing) {
+        Err(Error::File(err_path, err)) => {
+            assert_eq!(err_path, missing);
+            assert_eq!(err.kind(), ErrorKind::Io);
+        }
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+
+    match env.parse_module_reader(&b"(module\n  (func (export \"f\")))"[..]) {
+        Err(Error::Parse(err)) => assert!(err.message().contains("text format")),
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    assert!(env.parse_module_reader(STATEFUL).is_ok());
+}
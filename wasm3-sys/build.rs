@@ -15,6 +15,7 @@ fn gen_wrapper(out_path: &Path) -> PathBuf {
     let wrapper_file = out_path.join("wrapper.h");
     let header_files = [
         "wasm3.h",
+        "m3_env.h",
         #[cfg(feature = "wasi")]
         "m3_api_wasi.h",
     ];
@@ -63,6 +64,10 @@ fn gen_bindings() {
                 0
             }
         ))
+        .arg(format!(
+            "-Dd_m3RecordBacktraces={}",
+            if cfg!(feature = "backtrace") { 1 } else { 0 }
+        ))
         .arg("-Dd_m3LogOutput=0")
         .arg("-Iwasm3/source");
     let status = bindgen.status().expect("Unable to generate bindings");
@@ -102,6 +107,10 @@ fn gen_bindings() {
                         0
                     }
                 ),
+                &format!(
+                    "-Dd_m3RecordBacktraces={}",
+                    if cfg!(feature = "backtrace") { 1 } else { 0 }
+                ),
                 "-Dd_m3LogOutput=0",
                 "-Iwasm3/source",
             ]
@@ -118,14 +127,6 @@ fn main() {
 
     let mut cfg = cc::Build::new();
 
-    cfg.files(
-        fs::read_dir(WASM3_SOURCE)
-            .unwrap_or_else(|_| panic!("failed to read {} directory", WASM3_SOURCE))
-            .filter_map(Result::ok)
-            .map(|entry| entry.path())
-            .filter(|p| p.extension().and_then(OsStr::to_str) == Some("c")),
-    );
-
     cfg.cpp(false)
         .define("d_m3LogOutput", Some("0"))
         .warnings(false)
@@ -156,5 +157,33 @@ fn main() {
             Some("0")
         },
     );
+    cfg.define(
+        "d_m3RecordBacktraces",
+        if cfg!(feature = "backtrace") {
+            Some("1")
+        } else {
+            Some("0")
+        },
+    );
+
+    let sources = fs::read_dir(WASM3_SOURCE)
+        .unwrap_or_else(|_| panic!("failed to read {} directory", WASM3_SOURCE))
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|p| p.extension().and_then(OsStr::to_str) == Some("c"));
+    if cfg!(feature = "yield-hook") {
+        // wasm3's default m3_Yield in m3_core.c is only weak on some platforms, so it is renamed
+        // instead, leaving m3_Yield to be defined by the crate linking against this one
+        let core_source = Path::new(WASM3_SOURCE).join("m3_core.c");
+        let core_objects = cfg
+            .clone()
+            .file(&core_source)
+            .define("m3_Yield", Some("m3_DefaultYield"))
+            .compile_intermediates();
+        cfg.objects(core_objects)
+            .files(sources.filter(|p| *p != core_source));
+    } else {
+        cfg.files(sources);
+    }
     cfg.compile("wasm3");
 }